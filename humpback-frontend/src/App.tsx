@@ -7,7 +7,7 @@ import { Sidebar } from "./components/Sidebar";
 
 // Constants
 const API_BASE = "http://localhost:8080";
-const TOKEN = "humpback_secret_token_2024";
+const TOKEN = import.meta.env.VITE_HUMPBACK_TOKEN ?? "";
 
 // API Functions
 const api = {
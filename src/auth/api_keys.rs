@@ -0,0 +1,187 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::{Arc, RwLock},
+};
+
+use deno_core::serde_json;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use super::{Principal, Scope, constant_time_eq, hash_secret};
+use crate::kv::{
+    core::{Core, SetOptions},
+    expiry,
+    objects::{Kind, ScanRange},
+};
+
+// Tokens look like `hbk_<id>_<secret>`
+const TOKEN_PREFIX: &str = "hbk_";
+// Records are stored under `api_key:<id>` in the system database
+const RECORD_PREFIX: &str = "api_key:";
+
+/// What is known about an API key. Its secret is only shown once, when the
+/// key is created or rotated, and is stored hashed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// Keys the API key may touch, every key when empty
+    pub key_prefixes: Vec<String>,
+    pub created_at: u64,
+    pub rotated_at: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Record {
+    #[serde(flatten)]
+    key: ApiKey,
+    secret_sha256: String,
+}
+
+pub struct ApiKeys {
+    core: Arc<Core>,
+    records: RwLock<HashMap<String, Record>>,
+    // Keeps a rotation from bringing back a key revoked in the meantime
+    admin_lock: Mutex<()>,
+}
+
+impl ApiKeys {
+    pub fn load(core: Arc<Core>) -> Result<ApiKeys, Box<dyn Error + Send + Sync>> {
+        let mut records = HashMap::new();
+        for element in core.scan(ScanRange::Prefix(RECORD_PREFIX.to_string()), None, &[])? {
+            let Some(object) = core.get(&element.key) else {
+                continue;
+            };
+            let record: Record = serde_json::from_slice(&object.data)
+                .map_err(|e| format!("Invalid API key record '{}': {}", element.key, e))?;
+            records.insert(record.key.id.clone(), record);
+        }
+        if !records.is_empty() {
+            println!("Loaded {} API keys", records.len());
+        }
+        Ok(ApiKeys {
+            core,
+            records: RwLock::new(records),
+            admin_lock: Mutex::new(()),
+        })
+    }
+
+    pub fn authenticate(&self, token: &str) -> Option<Arc<Principal>> {
+        let (id, secret) = token.strip_prefix(TOKEN_PREFIX)?.split_once('_')?;
+        let records = self.records.read().unwrap();
+        let record = records.get(id)?;
+        if !constant_time_eq(
+            hash_secret(secret).as_bytes(),
+            record.secret_sha256.as_bytes(),
+        ) {
+            return None;
+        }
+        Some(Arc::new(Principal {
+            name: format!("api key {}", record.key.name),
+            scopes: record.key.scopes.clone(),
+            key_prefixes: record.key.key_prefixes.clone(),
+        }))
+    }
+
+    /// Every key, oldest first.
+    pub fn list(&self) -> Vec<ApiKey> {
+        let mut keys: Vec<ApiKey> = self
+            .records
+            .read()
+            .unwrap()
+            .values()
+            .map(|record| record.key.clone())
+            .collect();
+        keys.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+        keys
+    }
+
+    /// Returns the new key and the token to hand to the client.
+    pub async fn create(
+        &self,
+        name: String,
+        scopes: Vec<Scope>,
+        key_prefixes: Vec<String>,
+    ) -> Result<(ApiKey, String), Box<dyn Error + Send + Sync>> {
+        let _guard = self.admin_lock.lock().await;
+
+        let secret = random_hex(32);
+        let record = Record {
+            key: ApiKey {
+                id: random_hex(8),
+                name,
+                scopes,
+                key_prefixes,
+                created_at: expiry::now_millis(),
+                rotated_at: None,
+            },
+            secret_sha256: hash_secret(&secret),
+        };
+        self.save(record, &secret).await
+    }
+
+    /// Replaces the secret of a key, the old token stops working at once.
+    pub async fn rotate(&self, id: &str) -> Result<(ApiKey, String), Box<dyn Error + Send + Sync>> {
+        let _guard = self.admin_lock.lock().await;
+
+        let mut record = self
+            .records
+            .read()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or("API key not found")?;
+        let secret = random_hex(32);
+        record.secret_sha256 = hash_secret(&secret);
+        record.key.rotated_at = Some(expiry::now_millis());
+        self.save(record, &secret).await
+    }
+
+    pub async fn revoke(&self, id: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let _guard = self.admin_lock.lock().await;
+
+        if !self.records.read().unwrap().contains_key(id) {
+            return Err("API key not found".into());
+        }
+        self.core
+            .delete_soft_async(&format!("{}{}", RECORD_PREFIX, id))
+            .await?;
+        self.records.write().unwrap().remove(id);
+        Ok(())
+    }
+
+    // Persists the record before it is accepted, so a crash can't leave a
+    // handed out token unknown after the restart
+    async fn save(
+        &self,
+        record: Record,
+        secret: &str,
+    ) -> Result<(ApiKey, String), Box<dyn Error + Send + Sync>> {
+        let data = serde_json::to_vec(&record)?;
+        let key = format!("{}{}", RECORD_PREFIX, record.key.id);
+        self.core
+            .set_async(&key, Kind::Json, data, SetOptions::default())
+            .await?;
+
+        let token = format!("{}{}_{}", TOKEN_PREFIX, record.key.id, secret);
+        let api_key = record.key.clone();
+        self.records
+            .write()
+            .unwrap()
+            .insert(record.key.id.clone(), record);
+        Ok((api_key, token))
+    }
+}
+
+pub fn is_api_key(token: &str) -> bool {
+    token.starts_with(TOKEN_PREFIX)
+}
+
+pub(super) fn random_hex(bytes: usize) -> String {
+    let mut buf = vec![0u8; bytes];
+    rand::thread_rng().fill_bytes(&mut buf);
+    buf.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,267 @@
+use std::{collections::HashMap, error::Error, fmt, sync::Arc};
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::AuthConfig,
+    kv::{
+        core::{Core, SetOptions},
+        databases::Databases,
+        objects::Kind,
+    },
+};
+
+pub mod api_keys;
+
+// Hash of the token generated when no shared token is configured, kept in
+// the system database
+const DEFAULT_TOKEN_KEY: &str = "auth:default_token";
+
+use api_keys::ApiKeys;
+
+/// What a request may do. API keys carry scopes directly, configured users
+/// get them through their permissions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Scope {
+    Get,
+    Set,
+    Delete,
+    List,
+    /// Running stored scripts, which see every key of every database
+    Exec,
+    ExecNow,
+    /// Server maintenance and key management, implies every other scope
+    Admin,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Scope::Get => "get",
+            Scope::Set => "set",
+            Scope::Delete => "delete",
+            Scope::List => "list",
+            Scope::Exec => "exec",
+            Scope::ExecNow => "execNow",
+            Scope::Admin => "admin",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Coarse grants for configured users.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    Write,
+    Exec,
+    Admin,
+}
+
+impl Permission {
+    fn scopes(self) -> &'static [Scope] {
+        match self {
+            Permission::Read => &[Scope::Get, Scope::List],
+            Permission::Write => &[Scope::Set, Scope::Delete],
+            Permission::Exec => &[Scope::Exec, Scope::ExecNow],
+            Permission::Admin => &[Scope::Admin],
+        }
+    }
+}
+
+/// Whoever is behind a connection or a request, and what they may do.
+#[derive(Debug)]
+pub struct Principal {
+    pub name: String,
+    scopes: Vec<Scope>,
+    /// Keys the principal may touch, every key when empty
+    key_prefixes: Vec<String>,
+}
+
+impl Principal {
+    pub fn can(&self, scope: Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope)
+    }
+
+    pub fn key_prefixes(&self) -> &[String] {
+        &self.key_prefixes
+    }
+
+    pub fn can_access(&self, key: &str) -> bool {
+        self.key_prefixes.is_empty()
+            || self
+                .key_prefixes
+                .iter()
+                .any(|prefix| key.starts_with(prefix.as_str()))
+    }
+
+    /// Checks `scope`, on `key` when given. The error reads like a Redis
+    /// `NOPERM` reply so every front-end can pass it on as is.
+    pub fn check(&self, scope: Scope, key: Option<&str>) -> Result<(), String> {
+        if !self.can(scope) {
+            return Err(format!("NOPERM '{}' has no {} scope", self.name, scope));
+        }
+        if let Some(key) = key
+            && !self.can_access(key)
+        {
+            return Err(format!(
+                "NOPERM '{}' can not access key '{}'",
+                self.name, key
+            ));
+        }
+        Ok(())
+    }
+}
+
+struct User {
+    /// PHC string, e.g. `$argon2id$v=19$...`
+    password_hash: String,
+    principal: Arc<Principal>,
+}
+
+/// Users, shared tokens and API keys accepted by the HTTP, TCP and RESP
+/// front-ends.
+pub struct Acl {
+    users: HashMap<String, User>,
+    tokens: Vec<String>,
+    /// Set when no token is configured
+    default_token_sha256: Option<String>,
+    // Shared tokens predate users and keep granting full access
+    token_principal: Arc<Principal>,
+    pub api_keys: ApiKeys,
+}
+
+impl Acl {
+    /// Expects a config that went through `Config::validate`. API keys are
+    /// loaded from the system database. Without configured tokens, a random
+    /// one is generated on first start and printed once.
+    pub fn new(
+        config: &AuthConfig,
+        dbs: &Databases,
+    ) -> Result<Arc<Acl>, Box<dyn Error + Send + Sync>> {
+        let users = config
+            .users
+            .iter()
+            .map(|user| {
+                let principal = Principal {
+                    name: user.name.clone(),
+                    scopes: user
+                        .permissions
+                        .iter()
+                        .flat_map(|permission| permission.scopes())
+                        .copied()
+                        .collect(),
+                    key_prefixes: user.key_prefixes.clone(),
+                };
+                let user_entry = User {
+                    password_hash: user.password_hash.clone(),
+                    principal: Arc::new(principal),
+                };
+                (user.name.clone(), user_entry)
+            })
+            .collect();
+
+        let system = dbs.system()?;
+        let default_token_sha256 = if config.tokens.is_empty() {
+            Some(default_token(&system)?)
+        } else {
+            None
+        };
+
+        Ok(Arc::new(Acl {
+            users,
+            tokens: config.tokens.clone(),
+            default_token_sha256,
+            token_principal: Arc::new(Principal {
+                name: "default".to_string(),
+                scopes: vec![Scope::Admin],
+                key_prefixes: Vec::new(),
+            }),
+            api_keys: ApiKeys::load(system)?,
+        }))
+    }
+
+    /// Verifies the password on the blocking pool, argon2 is slow on purpose.
+    pub async fn login(&self, name: &str, password: &str) -> Option<Arc<Principal>> {
+        let user = self.users.get(name)?;
+        let password_hash = user.password_hash.clone();
+        let password = password.to_string();
+        let verified = tokio::task::spawn_blocking(move || {
+            let hash = PasswordHash::new(&password_hash).ok()?;
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .ok()
+        })
+        .await
+        .expect("spawn_blocking failed");
+        verified.map(|_| Arc::clone(&user.principal))
+    }
+
+    /// Accepts an API key, a shared token or `<user>:<password>`.
+    pub async fn authenticate_token(&self, token: &str) -> Option<Arc<Principal>> {
+        if api_keys::is_api_key(token) {
+            return self.api_keys.authenticate(token);
+        }
+        if self
+            .tokens
+            .iter()
+            .any(|t| constant_time_eq(t.as_bytes(), token.as_bytes()))
+        {
+            return Some(Arc::clone(&self.token_principal));
+        }
+        if let Some(sha256) = &self.default_token_sha256
+            && constant_time_eq(hash_secret(token).as_bytes(), sha256.as_bytes())
+        {
+            return Some(Arc::clone(&self.token_principal));
+        }
+        let (name, password) = token.split_once(':')?;
+        self.login(name, password).await
+    }
+}
+
+// Returns the hash of the generated token, creating the token when the
+// system database has none yet
+fn default_token(core: &Core) -> Result<String, Box<dyn Error + Send + Sync>> {
+    if let Some(object) = core.get(DEFAULT_TOKEN_KEY) {
+        return Ok(String::from_utf8(object.data)?);
+    }
+    let token = api_keys::random_hex(32);
+    let sha256 = hash_secret(&token);
+    core.set(
+        DEFAULT_TOKEN_KEY,
+        Kind::String,
+        sha256.clone().into_bytes(),
+        SetOptions::default(),
+    )?;
+    println!(
+        "No auth token configured, generated one (shown only once): {}",
+        token
+    );
+    Ok(sha256)
+}
+
+/// Lowercase hex SHA-256, the same as `printf %s <secret> | sha256sum`.
+pub fn hash_secret(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Whether `hash` is a PHC string `login` can verify.
+pub fn is_valid_password_hash(hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| hash.algorithm.as_str().starts_with("argon2"))
+}
+
+// Doesn't stop at the first difference, so timing tells nothing about
+// how much of a secret was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
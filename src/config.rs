@@ -0,0 +1,268 @@
+use std::{fs, path::Path, time::Duration};
+
+use clap::Parser;
+use serde::Deserialize;
+
+use crate::{
+    auth::{self, Permission},
+    kv::{core::Options, durability::Durability},
+};
+
+const DEFAULT_CONFIG_PATH: &str = "./humpback.toml";
+
+/// Settings of the whole server. Built from the defaults below, then the
+/// TOML file, then `HUMPBACK_*` environment variables, then CLI flags, each
+/// overriding the previous one.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub data_dir: String,
+    pub http: ListenConfig,
+    pub tcp: ListenConfig,
+    /// Redis protocol (RESP2/RESP3) listener, off unless configured
+    pub resp: Option<ListenConfig>,
+    pub auth: AuthConfig,
+    pub storage: StorageConfig,
+    pub js: JsConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenConfig {
+    pub listen: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Every token in the list is accepted and grants full access. When
+    /// empty, a token is generated on first start
+    pub tokens: Vec<String>,
+    pub users: Vec<UserConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub name: String,
+    /// Argon2 PHC string of the password, e.g.
+    /// `printf %s <password> | argon2 "$(openssl rand -base64 12)" -id -e`
+    pub password_hash: String,
+    pub permissions: Vec<Permission>,
+    /// Limits the user to keys starting with one of these
+    #[serde(default)]
+    pub key_prefixes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub durability: Durability,
+    pub cache_bytes: Option<usize>,
+    /// Databases clients may have open at once, `main` included
+    pub max_databases: usize,
+    /// Largest blob upload. Other writers wait while a blob is copied into
+    /// the data file
+    pub max_blob_bytes: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JsConfig {
+    /// Script driving the runtime's event loop
+    pub event_loop: String,
+    /// How long `/execNow` waits for a result
+    pub exec_timeout_ms: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            data_dir: "./humpback-data".to_string(),
+            http: ListenConfig {
+                listen: "127.0.0.1:8080".to_string(),
+            },
+            tcp: ListenConfig {
+                listen: "127.0.0.1:8081".to_string(),
+            },
+            resp: None,
+            auth: AuthConfig::default(),
+            storage: StorageConfig::default(),
+            js: JsConfig::default(),
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            durability: Durability::default(),
+            cache_bytes: None,
+            max_databases: 16,
+            max_blob_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for JsConfig {
+    fn default() -> Self {
+        JsConfig {
+            event_loop: "./eventLoop.js".to_string(),
+            exec_timeout_ms: 5000,
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+#[command(name = "humpback", version, about = "Humpback KV Database")]
+struct Args {
+    /// TOML config file, ./humpback.toml is used when present
+    #[arg(long, env = "HUMPBACK_CONFIG")]
+    config: Option<String>,
+    #[arg(long, env = "HUMPBACK_DATA_DIR")]
+    data_dir: Option<String>,
+    #[arg(long, env = "HUMPBACK_HTTP_LISTEN")]
+    http_listen: Option<String>,
+    #[arg(long, env = "HUMPBACK_TCP_LISTEN")]
+    tcp_listen: Option<String>,
+    /// Turns on the Redis protocol listener, e.g. 127.0.0.1:6379
+    #[arg(long, env = "HUMPBACK_RESP_LISTEN")]
+    resp_listen: Option<String>,
+    /// Replaces the configured tokens, repeat the flag or separate with commas
+    #[arg(
+        long = "auth-token",
+        env = "HUMPBACK_AUTH_TOKENS",
+        value_delimiter = ','
+    )]
+    auth_tokens: Vec<String>,
+    /// always | group[:<ms>] | os
+    #[arg(long, env = "HUMPBACK_DURABILITY")]
+    durability: Option<Durability>,
+    /// Enables lazy loading with a value cache of this many bytes
+    #[arg(long, env = "HUMPBACK_CACHE_BYTES")]
+    cache_bytes: Option<usize>,
+    #[arg(long, env = "HUMPBACK_MAX_DATABASES")]
+    max_databases: Option<usize>,
+    #[arg(long, env = "HUMPBACK_MAX_BLOB_BYTES")]
+    max_blob_bytes: Option<u64>,
+    #[arg(long, env = "HUMPBACK_JS_EVENT_LOOP")]
+    js_event_loop: Option<String>,
+    #[arg(long, env = "HUMPBACK_JS_EXEC_TIMEOUT_MS")]
+    js_exec_timeout_ms: Option<u64>,
+}
+
+impl Config {
+    pub fn load() -> Result<Config, String> {
+        let args = Args::parse();
+
+        let mut config = match &args.config {
+            Some(path) => Config::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Config::from_file(DEFAULT_CONFIG_PATH)?
+            }
+            None => Config::default(),
+        };
+
+        if let Some(data_dir) = args.data_dir {
+            config.data_dir = data_dir;
+        }
+        if let Some(listen) = args.http_listen {
+            config.http.listen = listen;
+        }
+        if let Some(listen) = args.tcp_listen {
+            config.tcp.listen = listen;
+        }
+        if let Some(listen) = args.resp_listen {
+            config.resp = Some(ListenConfig { listen });
+        }
+        if !args.auth_tokens.is_empty() {
+            config.auth.tokens = args.auth_tokens;
+        }
+        if let Some(durability) = args.durability {
+            config.storage.durability = durability;
+        }
+        if let Some(cache_bytes) = args.cache_bytes {
+            config.storage.cache_bytes = Some(cache_bytes);
+        }
+        if let Some(max_databases) = args.max_databases {
+            config.storage.max_databases = max_databases;
+        }
+        if let Some(max_blob_bytes) = args.max_blob_bytes {
+            config.storage.max_blob_bytes = max_blob_bytes;
+        }
+        if let Some(event_loop) = args.js_event_loop {
+            config.js.event_loop = event_loop;
+        }
+        if let Some(timeout) = args.js_exec_timeout_ms {
+            config.js.exec_timeout_ms = timeout;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &str) -> Result<Config, String> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+        let config = toml::from_str(&contents).map_err(|e| format!("Invalid {}: {}", path, e))?;
+        println!("Loaded config from {}", path);
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.auth.tokens.iter().any(|token| token.is_empty()) {
+            return Err("Auth tokens can not be empty".to_string());
+        }
+        for (i, user) in self.auth.users.iter().enumerate() {
+            if user.name.is_empty() || user.name.contains(':') {
+                return Err(format!("Invalid user name '{}'", user.name));
+            }
+            if self.auth.users[..i].iter().any(|u| u.name == user.name) {
+                return Err(format!("User '{}' is defined twice", user.name));
+            }
+            if !auth::is_valid_password_hash(&user.password_hash) {
+                return Err(format!(
+                    "Password of user '{}' must be an argon2 PHC string",
+                    user.name
+                ));
+            }
+        }
+        if self.storage.max_databases == 0 {
+            return Err("storage.max_databases must be at least 1, for main".to_string());
+        }
+        // Record headers store the length in 32 bits
+        if self.storage.max_blob_bytes == 0 || self.storage.max_blob_bytes > u32::MAX as u64 {
+            return Err(format!(
+                "storage.max_blob_bytes must be between 1 and {}",
+                u32::MAX
+            ));
+        }
+        let mut listeners = vec![("HTTP", &self.http.listen), ("TCP", &self.tcp.listen)];
+        if let Some(resp) = &self.resp {
+            listeners.push(("RESP", &resp.listen));
+        }
+        for (i, (name, listen)) in listeners.iter().enumerate() {
+            if let Some((other, _)) = listeners[i + 1..].iter().find(|(_, l)| l == listen) {
+                return Err(format!(
+                    "{} and {} can not both listen on {}",
+                    name, other, listen
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn core_options(&self) -> Options {
+        Options {
+            data_dir: self.data_dir.clone(),
+            durability: self.storage.durability,
+            cache_bytes: self.storage.cache_bytes,
+        }
+    }
+}
+
+impl JsConfig {
+    pub fn exec_timeout(&self) -> Duration {
+        Duration::from_millis(self.exec_timeout_ms)
+    }
+}
@@ -1,80 +1,212 @@
 use crate::{
+    auth::{Acl, Principal, Scope},
+    config::Config,
     js::{self, event::Event, runtime::Runtime},
-    kv::{core::Core, objects::Kind},
+    kv::{
+        blob::BlobReader,
+        core::{Condition, Core, ListOptions, SetOptions},
+        databases::Databases,
+        expiry,
+        objects::{Kind, ListCursor, MAX_KEY_LEN, ScanRange},
+        pattern::KeyPattern,
+        transaction::Transaction,
+    },
+    shutdown::Shutdown,
 };
 use axum::{
-    Router,
-    extract::{Json, State},
-    http::StatusCode,
-    response::{Html, Json as ResponseJson},
+    Extension, Router,
+    body::Body,
+    body::Bytes,
+    extract::{Json, Path, Query, Request, State},
+    http::{HeaderMap, HeaderValue, Method, StatusCode, header},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Json as ResponseJson, Response},
     routing::{get, post},
 };
 use deno_core::serde_json::{self, json};
+use futures::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, error::Error, io::Write, str::FromStr, sync::Arc, time::Duration};
-use tokio::{signal, sync::Notify, time::timeout};
+use std::{collections::HashMap, error::Error, str::FromStr, sync::Arc, time::Duration};
+use tokio::{io::AsyncWriteExt, sync::Notify, time::timeout};
 use tower::ServiceBuilder;
 use tower_http::{cors::CorsLayer, trace::TraceLayer};
 
-const AUTH_TOKEN: &str = "humpback_secret_token_2024";
-
 #[derive(Clone)]
 pub struct AppState {
-    pub core: Arc<Core>,
+    pub dbs: Arc<Databases>,
     pub runtime: Arc<Runtime>,
+    pub acl: Arc<Acl>,
+    pub exec_timeout: Duration,
+    pub max_blob_bytes: u64,
+}
+
+// Kind of a value on the REST routes, `kind` in the query works as well
+const KIND_HEADER: &str = "x-humpback-kind";
+
+// Same as the limit axum puts on `Json` bodies
+const MAX_JSON_BODY: usize = 2 * 1024 * 1024;
+const BLOB_CHUNK_SIZE: usize = 64 * 1024;
+
+// Older clients send the token in the JSON body instead of a header
+#[derive(Deserialize)]
+struct BodyToken {
+    token: Option<String>,
 }
 
 #[derive(Deserialize)]
 struct BaseRequest {
-    token: String,
+    db: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CreateKeyRequest {
+    name: String,
+    scopes: Vec<Scope>,
+    #[serde(default)]
+    key_prefixes: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct KeyIdRequest {
+    id: String,
 }
 
 #[derive(Deserialize)]
 struct GetRequest {
-    token: String,
+    db: Option<String>,
     key: String,
 }
 
 #[derive(Deserialize)]
 struct SetRequest {
-    token: String,
+    db: Option<String>,
     key: String,
     kind: String,
     data: String,
+    ttl: Option<u64>,
+    #[serde(flatten)]
+    condition: ConditionRequest,
+}
+
+#[derive(Deserialize)]
+struct ConditionRequest {
+    if_version: Option<u64>,
+    #[serde(default)]
+    if_absent: bool,
+    #[serde(default)]
+    if_present: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum TxnOpRequest {
+    Set {
+        key: String,
+        kind: String,
+        data: String,
+        ttl: Option<u64>,
+        #[serde(flatten)]
+        condition: ConditionRequest,
+    },
+    Delete {
+        key: String,
+    },
+}
+
+#[derive(Deserialize)]
+struct TxnRequest {
+    db: Option<String>,
+    ops: Vec<TxnOpRequest>,
+}
+
+#[derive(Deserialize)]
+struct IncrRequest {
+    db: Option<String>,
+    key: String,
+    delta: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct TtlRequest {
+    db: Option<String>,
+    key: String,
 }
 
 #[derive(Deserialize)]
 struct DeleteRequest {
-    token: String,
+    db: Option<String>,
     key: String,
 }
 
+#[derive(Deserialize)]
+struct ListQuery {
+    // Glob like `cache:*:v2`, or a regular expression when `regex` is set
+    pattern: Option<String>,
+    #[serde(default)]
+    regex: bool,
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
 #[derive(Deserialize)]
 struct ListRequest {
-    token: String,
+    db: Option<String>,
+    #[serde(flatten)]
+    query: ListQuery,
 }
 
 #[derive(Deserialize)]
 struct ListTypeRequest {
-    token: String,
+    db: Option<String>,
     kind: String,
+    #[serde(flatten)]
+    query: ListQuery,
+}
+
+#[derive(Deserialize)]
+struct ScanRequest {
+    db: Option<String>,
+    prefix: Option<String>,
+    start: Option<String>,
+    end: Option<String>,
+    limit: Option<usize>,
 }
 
 #[derive(Deserialize)]
 struct ExecRequest {
-    token: String,
+    db: Option<String>,
     key: String,
 }
 #[derive(Deserialize)]
 struct ExecNowRequest {
-    token: String,
     code: String,
 }
 
+#[derive(Deserialize)]
+struct KeyQuery {
+    db: Option<String>,
+    kind: Option<String>,
+    ttl: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct KeysQuery {
+    db: Option<String>,
+    prefix: Option<String>,
+    kind: Option<String>,
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
 #[derive(Serialize)]
 struct SuccessResponse {
     status: String,
     data: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<u64>,
+    // Continuation token of a paged listing, absent on the last page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cursor: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -88,27 +220,55 @@ struct ListItem {
     key: String,
     kind: String,
     size: usize,
+    version: u64,
 }
 
 type ApiResult<T> = Result<ResponseJson<T>, (StatusCode, ResponseJson<ErrorResponse>)>;
 
-#[tokio::main]
-pub async fn run(core: Arc<Core>) -> Result<(), Box<dyn Error>> {
-    let runtime: Arc<Runtime> = js::runtime::Runtime::new(Arc::clone(&core));
+pub async fn run(
+    dbs: Arc<Databases>,
+    runtime: Arc<Runtime>,
+    acl: Arc<Acl>,
+    config: &Config,
+    mut shutdown: Shutdown,
+) -> Result<(), Box<dyn Error>> {
     let state = AppState {
-        core: Arc::clone(&core),
+        dbs: Arc::clone(&dbs),
         runtime,
+        acl,
+        exec_timeout: config.js.exec_timeout(),
+        max_blob_bytes: config.storage.max_blob_bytes,
     };
 
     let app = Router::new()
-        .route("/", get(serve_html))
         .route("/get", post(handle_get))
         .route("/set", post(handle_set))
         .route("/delete", post(handle_delete))
+        .route("/incr", post(handle_incr))
+        .route("/txn", post(handle_txn))
+        .route("/ttl", post(handle_ttl))
+        .route("/persist", post(handle_persist))
         .route("/list", post(handle_list))
         .route("/listType", post(handle_list_type))
+        .route("/scan", post(handle_scan))
         .route("/exec", post(handle_exec))
         .route("/execNow", post(handle_exec_now))
+        .route("/keys", get(handle_keys_list))
+        .route(
+            "/keys/*key",
+            // `get` answers HEAD as well, with the same headers and no body
+            get(handle_key_get)
+                .put(handle_key_put)
+                .delete(handle_key_delete),
+        )
+        .route("/blobs/*key", get(handle_blob_get).put(handle_blob_put))
+        .route("/admin/compact", post(handle_compact))
+        .route("/admin/keys", post(handle_key_list))
+        .route("/admin/keys/create", post(handle_key_create))
+        .route("/admin/keys/rotate", post(handle_key_rotate))
+        .route("/admin/keys/revoke", post(handle_key_revoke))
+        .route_layer(middleware::from_fn_with_state(state.clone(), authenticate))
+        .route("/", get(serve_html))
         .layer(
             ServiceBuilder::new()
                 .layer(TraceLayer::new_for_http())
@@ -116,47 +276,106 @@ pub async fn run(core: Arc<Core>) -> Result<(), Box<dyn Error>> {
         )
         .with_state(state);
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:8080").await?;
-    println!("Humpback KV Database HTTP Server is listening on 127.0.0.1:8080");
-
-    // Graceful shutdown
-    tokio::spawn(async move {
-        signal::ctrl_c().await.expect("Failed to listen for ctrl+c");
-        println!("Exit signal received\nInitiating graceful shutdown...");
-    });
+    let listener = tokio::net::TcpListener::bind(&config.http.listen).await?;
+    println!(
+        "Humpback KV Database HTTP Server is listening on {}",
+        config.http.listen
+    );
 
     axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
+        .with_graceful_shutdown(async move { shutdown.recv().await })
         .await?;
 
-    // Cleanup
-    tokio::time::sleep(Duration::from_millis(500)).await;
+    println!("HTTP server stopped");
+    Ok(())
+}
+
+// Resolves the caller of every API route from `Authorization: Bearer
+// <token>`, or from the `token` field of a JSON body on the POST routes,
+// and hands it to the handler as an `Arc<Principal>` extension.
+async fn authenticate(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, (StatusCode, ResponseJson<ErrorResponse>)> {
+    let (mut parts, body) = request.into_parts();
+    let (token, body) = match bearer_token(&parts.headers) {
+        Some(token) => (Some(token), body),
+        // REST bodies are values, a `token` in them is just data
+        None if parts.method == Method::POST && is_json(&parts.headers) => {
+            // Buffered here, so the handler gets the same bytes back
+            let bytes = axum::body::to_bytes(body, MAX_JSON_BODY)
+                .await
+                .map_err(|_| create_error_response("Request body is too large"))?;
+            let token = serde_json::from_slice::<BodyToken>(&bytes)
+                .ok()
+                .and_then(|body| body.token);
+            (token, Body::from(bytes))
+        }
+        None => (None, body),
+    };
 
-    let mut desc_file = core.desc_file.lock().unwrap();
-    desc_file.flush()?;
+    let Some(token) = token else {
+        return Err(unauthorized("Missing token"));
+    };
+    let principal = state
+        .acl
+        .authenticate_token(&token)
+        .await
+        .ok_or_else(|| unauthorized("Invalid token"))?;
+    parts.extensions.insert(principal);
+    Ok(next.run(Request::from_parts(parts, body)).await)
+}
 
-    let mut data_file = core.data_file.lock().unwrap();
-    data_file.flush()?;
+fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    Some(token.trim().to_string())
+}
 
-    println!("All data flushed to disk");
-    println!("Resources released");
-    Ok(())
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"))
 }
 
-async fn shutdown_signal() {
-    signal::ctrl_c()
-        .await
-        .expect("Failed to install CTRL+C signal handler");
+// Checks the caller has `scope`, on `key` when given
+fn require(
+    principal: &Principal,
+    scope: Scope,
+    key: Option<&str>,
+) -> Result<(), (StatusCode, ResponseJson<ErrorResponse>)> {
+    principal.check(scope, key).map_err(|e| forbidden(&e))
 }
 
-fn verify_token(token: &str) -> bool {
-    token == AUTH_TOKEN
+fn unauthorized(error: &str) -> (StatusCode, ResponseJson<ErrorResponse>) {
+    let (_, error) = create_error_response(error);
+    (StatusCode::UNAUTHORIZED, error)
+}
+
+fn forbidden(error: &str) -> (StatusCode, ResponseJson<ErrorResponse>) {
+    let (_, error) = create_error_response(error);
+    (StatusCode::FORBIDDEN, error)
 }
 
 async fn serve_html() -> Html<&'static str> {
     Html(include_str!("../index.html"))
 }
 
+fn open_db(
+    state: &AppState,
+    db: Option<&str>,
+) -> Result<Arc<Core>, (StatusCode, ResponseJson<ErrorResponse>)> {
+    state
+        .dbs
+        .get(db)
+        .map_err(|e| create_error_response(&e.to_string()))
+}
+
 fn create_error_response(error: &str) -> (StatusCode, ResponseJson<ErrorResponse>) {
     (
         StatusCode::BAD_REQUEST,
@@ -171,19 +390,21 @@ fn create_success_response(data: Option<serde_json::Value>) -> ResponseJson<Succ
     ResponseJson(SuccessResponse {
         status: "success".to_string(),
         data,
+        version: None,
+        cursor: None,
     })
 }
 
 async fn handle_get(
     State(state): State<AppState>,
+    Extension(principal): Extension<Arc<Principal>>,
     Json(request): Json<GetRequest>,
 ) -> ApiResult<SuccessResponse> {
-    if !verify_token(&request.token) {
-        return Err(create_error_response("Invalid token"));
-    }
+    require(&principal, Scope::Get, Some(&request.key))?;
+    let core = open_db(&state, request.db.as_deref())?;
 
     let start = std::time::Instant::now();
-    let object = state.core.get_async(&request.key).await;
+    let object = core.get_async(&request.key).await;
     let duration = start.elapsed();
     println!("GET completed in {:.2?}", duration);
 
@@ -207,80 +428,156 @@ async fn handle_get(
                     serde_json::from_str(&json_str)
                         .unwrap_or(serde_json::Value::String(json_str.to_string()))
                 }
+                Kind::Blob => {
+                    return Err(create_error_response(
+                        "Blob values are binary, download them from GET /blobs/{key}",
+                    ));
+                }
                 _ => {
                     let string_data = String::from_utf8_lossy(&object.data);
                     serde_json::Value::String(string_data.to_string())
                 }
             };
 
-            Ok(create_success_response(Some(data)))
+            let mut response = create_success_response(Some(data));
+            response.version = Some(object.desc.version);
+            Ok(response)
         }
         None => Err(create_error_response("Not found")),
     }
 }
 
-async fn handle_set(
-    State(state): State<AppState>,
-    Json(request): Json<SetRequest>,
-) -> ApiResult<SuccessResponse> {
-    if !verify_token(&request.token) {
-        return Err(create_error_response("Invalid token"));
+fn check_key(key: &str) -> Result<(), (StatusCode, ResponseJson<ErrorResponse>)> {
+    if key.len() > MAX_KEY_LEN {
+        return Err(create_error_response(&format!(
+            "Key is too long. Max key length - {} bytes",
+            MAX_KEY_LEN
+        )));
     }
+    Ok(())
+}
 
-    if request.key.len() > 256 {
-        return Err(create_error_response(
-            "Key is too long. Max key length - 256 bytes",
-        ));
-    }
+// TTLs come in seconds
+fn parse_ttl(
+    ttl: Option<u64>,
+) -> Result<Option<Duration>, (StatusCode, ResponseJson<ErrorResponse>)> {
+    ttl.map(|secs| expiry::check_ttl(Duration::from_secs(secs)).map_err(create_error_response))
+        .transpose()
+}
+
+fn parse_set_data(
+    key: &str,
+    kind: &str,
+    data: String,
+) -> Result<(Kind, Vec<u8>), (StatusCode, ResponseJson<ErrorResponse>)> {
+    check_key(key)?;
 
-    let kind = match Kind::from_str(&request.kind) {
+    let kind = match Kind::from_str(kind) {
         Ok(k) => k,
         Err(_) => return Err(create_error_response("Unknown kind")),
     };
 
     let data_buf = match kind {
         Kind::Number => {
-            let number: f64 = request
-                .data
+            let number: f64 = data
                 .parse()
                 .map_err(|_| create_error_response("Invalid number format"))?;
             number.to_le_bytes().to_vec()
         }
         Kind::Boolean => {
-            let boolean: bool = request
-                .data
+            let boolean: bool = data
                 .parse()
                 .map_err(|_| create_error_response("Invalid boolean format"))?;
             vec![if boolean { 1 } else { 0 }]
         }
-        _ => request.data.into_bytes(),
+        _ => data.into_bytes(),
+    };
+    Ok((kind, data_buf))
+}
+
+fn parse_list_options(
+    query: ListQuery,
+) -> Result<ListOptions, (StatusCode, ResponseJson<ErrorResponse>)> {
+    let pattern = match query.pattern {
+        Some(pattern) if query.regex => {
+            Some(KeyPattern::regex(&pattern).map_err(|e| create_error_response(&e))?)
+        }
+        Some(pattern) => Some(KeyPattern::glob(&pattern)),
+        None => None,
+    };
+    let cursor = query
+        .cursor
+        .map(|cursor| ListCursor::from_str(&cursor).map_err(|e| create_error_response(&e)))
+        .transpose()?;
+    Ok(ListOptions {
+        pattern,
+        cursor,
+        limit: query.limit,
+        ..Default::default()
+    })
+}
+
+fn parse_condition(
+    request: &ConditionRequest,
+) -> Result<Option<Condition>, (StatusCode, ResponseJson<ErrorResponse>)> {
+    match (request.if_version, request.if_absent, request.if_present) {
+        (None, false, false) => Ok(None),
+        (Some(version), false, false) => Ok(Some(Condition::Version(version))),
+        (None, true, false) => Ok(Some(Condition::Absent)),
+        (None, false, true) => Ok(Some(Condition::Present)),
+        _ => Err(create_error_response(
+            "Use only one of if_version, if_absent and if_present",
+        )),
+    }
+}
+
+async fn handle_set(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Arc<Principal>>,
+    Json(request): Json<SetRequest>,
+) -> ApiResult<SuccessResponse> {
+    require(&principal, Scope::Set, Some(&request.key))?;
+    let core = open_db(&state, request.db.as_deref())?;
+
+    let (kind, data_buf) = parse_set_data(&request.key, &request.kind, request.data)?;
+
+    let options = SetOptions {
+        ttl: parse_ttl(request.ttl)?,
+        condition: parse_condition(&request.condition)?,
     };
 
     let start = std::time::Instant::now();
-    state
-        .core
-        .set_async(&request.key, kind, data_buf.clone())
-        .await;
+    let size = data_buf.len();
+    let version = core
+        .set_async(&request.key, kind, data_buf, options)
+        .await
+        .map_err(|e| create_error_response(&e.to_string()))?;
     let duration = start.elapsed();
-    println!(
-        "SET completed in {:.2?} ({} bytes)",
-        duration,
-        data_buf.len()
-    );
+    println!("SET completed in {:.2?} ({} bytes)", duration, size);
 
-    Ok(create_success_response(None))
+    match version {
+        Some(version) => {
+            let mut response = create_success_response(None);
+            response.version = Some(version);
+            Ok(response)
+        }
+        None => {
+            let (_, error) = create_error_response("Condition failed");
+            Err((StatusCode::CONFLICT, error))
+        }
+    }
 }
 
 async fn handle_delete(
     State(state): State<AppState>,
+    Extension(principal): Extension<Arc<Principal>>,
     Json(request): Json<DeleteRequest>,
 ) -> ApiResult<SuccessResponse> {
-    if !verify_token(&request.token) {
-        return Err(create_error_response("Invalid token"));
-    }
+    require(&principal, Scope::Delete, Some(&request.key))?;
+    let core = open_db(&state, request.db.as_deref())?;
 
     let start = std::time::Instant::now();
-    match state.core.delete_soft_async(&request.key).await {
+    match core.delete_soft_async(&request.key).await {
         Ok(_) => {
             let duration = start.elapsed();
             println!("DELETE completed in {:.2?}", duration);
@@ -290,32 +587,135 @@ async fn handle_delete(
     }
 }
 
+async fn handle_incr(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Arc<Principal>>,
+    Json(request): Json<IncrRequest>,
+) -> ApiResult<SuccessResponse> {
+    require(&principal, Scope::Set, Some(&request.key))?;
+    check_key(&request.key)?;
+    let core = open_db(&state, request.db.as_deref())?;
+
+    let start = std::time::Instant::now();
+    match core
+        .incr_async(&request.key, request.delta.unwrap_or(1.0))
+        .await
+    {
+        Ok(number) => {
+            let duration = start.elapsed();
+            println!("INCR completed in {:.2?}", duration);
+            Ok(create_success_response(Some(json!(number))))
+        }
+        Err(e) => Err(create_error_response(&e.to_string())),
+    }
+}
+
+async fn handle_txn(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Arc<Principal>>,
+    Json(request): Json<TxnRequest>,
+) -> ApiResult<SuccessResponse> {
+    let core = open_db(&state, request.db.as_deref())?;
+
+    let mut txn = Transaction::new();
+    for op in request.ops {
+        match op {
+            TxnOpRequest::Set {
+                key,
+                kind,
+                data,
+                ttl,
+                condition,
+            } => {
+                require(&principal, Scope::Set, Some(&key))?;
+                let (kind, data_buf) = parse_set_data(&key, &kind, data)?;
+                let options = SetOptions {
+                    ttl: parse_ttl(ttl)?,
+                    condition: parse_condition(&condition)?,
+                };
+                txn.set(&key, kind, data_buf, options);
+            }
+            TxnOpRequest::Delete { key } => {
+                require(&principal, Scope::Delete, Some(&key))?;
+                txn.delete(&key)
+            }
+        }
+    }
+
+    let start = std::time::Instant::now();
+    let ops = txn.len();
+    match core.commit_async(txn).await {
+        Ok(_) => {
+            let duration = start.elapsed();
+            println!("TXN completed in {:.2?} ({} ops)", duration, ops);
+            Ok(create_success_response(None))
+        }
+        Err(e) => Err(create_error_response(&format!("Transaction failed: {}", e))),
+    }
+}
+
+async fn handle_ttl(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Arc<Principal>>,
+    Json(request): Json<TtlRequest>,
+) -> ApiResult<SuccessResponse> {
+    require(&principal, Scope::Get, Some(&request.key))?;
+    let core = open_db(&state, request.db.as_deref())?;
+
+    match core.ttl(&request.key) {
+        Some(Some(ttl)) => Ok(create_success_response(Some(json!(ttl.as_secs())))),
+        Some(None) => Ok(create_success_response(Some(serde_json::Value::Null))),
+        None => Err(create_error_response("Not found")),
+    }
+}
+
+async fn handle_persist(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Arc<Principal>>,
+    Json(request): Json<TtlRequest>,
+) -> ApiResult<SuccessResponse> {
+    require(&principal, Scope::Set, Some(&request.key))?;
+    let core = open_db(&state, request.db.as_deref())?;
+
+    match core.set_expiry_async(&request.key, None).await {
+        Ok(_) => Ok(create_success_response(None)),
+        Err(_) => Err(create_error_response("Not found")),
+    }
+}
+
 async fn handle_list(
     State(state): State<AppState>,
+    Extension(principal): Extension<Arc<Principal>>,
     Json(request): Json<ListRequest>,
 ) -> ApiResult<SuccessResponse> {
-    if !verify_token(&request.token) {
-        return Err(create_error_response("Invalid token"));
-    }
+    require(&principal, Scope::List, None)?;
+    let core = open_db(&state, request.db.as_deref())?;
+
+    let mut options = parse_list_options(request.query)?;
+    options.key_prefixes = principal.key_prefixes().to_vec();
 
     let start = std::time::Instant::now();
-    match state.core.list().await {
-        Ok(list) => {
-            let items: Vec<ListItem> = list
+    match core.list(options).await {
+        Ok(page) => {
+            let items: Vec<ListItem> = page
+                .items
                 .iter()
                 .map(|element| ListItem {
                     key: element.key.clone(),
                     kind: element.kind.to_string(),
                     size: element.size as usize,
+                    version: element.version,
                 })
                 .collect();
 
             let duration = start.elapsed();
             println!("LIST completed in {:.2?}", duration);
 
-            Ok(create_success_response(Some(
+            let mut response = create_success_response(Some(
                 serde_json::to_value(items).unwrap_or(serde_json::Value::Array(vec![])),
-            )))
+            ));
+            response.cursor = page.cursor.map(|cursor| cursor.to_string());
+            Ok(response)
         }
         Err(_) => Err(create_error_response("Unable to list objects")),
     }
@@ -323,19 +723,65 @@ async fn handle_list(
 
 async fn handle_list_type(
     State(state): State<AppState>,
+    Extension(principal): Extension<Arc<Principal>>,
     Json(request): Json<ListTypeRequest>,
 ) -> ApiResult<SuccessResponse> {
-    if !verify_token(&request.token) {
-        return Err(create_error_response("Invalid token"));
-    }
+    require(&principal, Scope::List, None)?;
+    let core = open_db(&state, request.db.as_deref())?;
 
     let kind_enum = match Kind::from_str(&request.kind) {
         Ok(k) => k,
         Err(_) => return Err(create_error_response("Invalid type")),
     };
 
+    let mut options = parse_list_options(request.query)?;
+    options.key_prefixes = principal.key_prefixes().to_vec();
+
     let start = std::time::Instant::now();
-    match state.core.list_by_kind(kind_enum).await {
+    match core.list_by_kind(kind_enum, options).await {
+        Ok(page) => {
+            let items: Vec<ListItem> = page
+                .items
+                .iter()
+                .map(|element| ListItem {
+                    key: element.key.clone(),
+                    kind: element.kind.to_string(),
+                    size: element.size as usize,
+                    version: element.version,
+                })
+                .collect();
+
+            let duration = start.elapsed();
+            println!("LIST_TYPE completed in {:.2?}", duration);
+
+            let mut response = create_success_response(Some(
+                serde_json::to_value(items).unwrap_or(serde_json::Value::Array(vec![])),
+            ));
+            response.cursor = page.cursor.map(|cursor| cursor.to_string());
+            Ok(response)
+        }
+        Err(_) => Err(create_error_response("Unable to list objects")),
+    }
+}
+
+async fn handle_scan(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Arc<Principal>>,
+    Json(request): Json<ScanRequest>,
+) -> ApiResult<SuccessResponse> {
+    require(&principal, Scope::List, None)?;
+    let core = open_db(&state, request.db.as_deref())?;
+
+    let range = match request.prefix {
+        Some(prefix) => ScanRange::Prefix(prefix),
+        None => ScanRange::Range {
+            start: request.start.unwrap_or_default(),
+            end: request.end,
+        },
+    };
+
+    let start = std::time::Instant::now();
+    match core.scan(range, request.limit, principal.key_prefixes()) {
         Ok(list) => {
             let items: Vec<ListItem> = list
                 .iter()
@@ -343,29 +789,30 @@ async fn handle_list_type(
                     key: element.key.clone(),
                     kind: element.kind.to_string(),
                     size: element.size as usize,
+                    version: element.version,
                 })
                 .collect();
 
             let duration = start.elapsed();
-            println!("LIST_TYPE completed in {:.2?}", duration);
+            println!("SCAN completed in {:.2?}", duration);
 
             Ok(create_success_response(Some(
                 serde_json::to_value(items).unwrap_or(serde_json::Value::Array(vec![])),
             )))
         }
-        Err(_) => Err(create_error_response("Unable to list objects")),
+        Err(_) => Err(create_error_response("Unable to scan objects")),
     }
 }
 
 async fn handle_exec(
     State(state): State<AppState>,
+    Extension(principal): Extension<Arc<Principal>>,
     Json(request): Json<ExecRequest>,
 ) -> ApiResult<SuccessResponse> {
-    if !verify_token(&request.token) {
-        return Err(create_error_response("Invalid token"));
-    }
+    require(&principal, Scope::Exec, Some(&request.key))?;
+    let core = open_db(&state, request.db.as_deref())?;
 
-    let object = state.core.get_async(&request.key).await;
+    let object = core.get_async(&request.key).await;
     match object {
         Some(object) => match String::from_utf8(object.data) {
             Ok(code) => {
@@ -381,16 +828,617 @@ async fn handle_exec(
 
 async fn handle_exec_now(
     State(state): State<AppState>,
+    Extension(principal): Extension<Arc<Principal>>,
     Json(request): Json<ExecNowRequest>,
 ) -> ApiResult<SuccessResponse> {
-    if !verify_token(&request.token) {
-        return Err(create_error_response("Invalid token"));
-    }
+    require(&principal, Scope::ExecNow, None)?;
     let event = js::event::Event::new_code_event(request.code);
     let rx = state.runtime.push_event(event);
-    if let Ok(Ok(mut response)) = timeout(Duration::from_secs(5), rx).await {
+    if let Ok(Ok(mut response)) = timeout(state.exec_timeout, rx).await {
         let resp = response.take();
         return Ok(create_success_response(Some(resp)));
     }
     Ok(create_success_response(None))
 }
+
+async fn handle_compact(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Arc<Principal>>,
+    request: Option<Json<BaseRequest>>,
+) -> ApiResult<SuccessResponse> {
+    require(&principal, Scope::Admin, None)?;
+    // With the token in a header there may be no body at all
+    let db = request.and_then(|Json(request)| request.db);
+    let core = open_db(&state, db.as_deref())?;
+
+    let start = std::time::Instant::now();
+    match core.compact_async().await {
+        Ok(stats) => {
+            let duration = start.elapsed();
+            println!("COMPACT completed in {:.2?}", duration);
+            Ok(create_success_response(Some(
+                serde_json::to_value(stats).unwrap_or(serde_json::Value::Null),
+            )))
+        }
+        Err(e) => Err(create_error_response(&format!("Compaction failed: {}", e))),
+    }
+}
+
+async fn handle_key_list(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Arc<Principal>>,
+) -> ApiResult<SuccessResponse> {
+    require(&principal, Scope::Admin, None)?;
+
+    let keys = state.acl.api_keys.list();
+    Ok(create_success_response(Some(
+        serde_json::to_value(keys).unwrap_or(serde_json::Value::Array(vec![])),
+    )))
+}
+
+async fn handle_key_create(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Arc<Principal>>,
+    Json(request): Json<CreateKeyRequest>,
+) -> ApiResult<SuccessResponse> {
+    require(&principal, Scope::Admin, None)?;
+    if request.name.is_empty() || request.scopes.is_empty() {
+        return Err(create_error_response(
+            "An API key needs a name and at least one scope",
+        ));
+    }
+
+    match state
+        .acl
+        .api_keys
+        .create(request.name, request.scopes, request.key_prefixes)
+        .await
+    {
+        Ok((key, token)) => {
+            println!("Created API key {} ({})", key.id, key.name);
+            // The only time the token is shown
+            Ok(create_success_response(Some(
+                json!({ "api_key": key, "token": token }),
+            )))
+        }
+        Err(e) => Err(create_error_response(&format!(
+            "Unable to create API key: {}",
+            e
+        ))),
+    }
+}
+
+async fn handle_key_rotate(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Arc<Principal>>,
+    Json(request): Json<KeyIdRequest>,
+) -> ApiResult<SuccessResponse> {
+    require(&principal, Scope::Admin, None)?;
+
+    match state.acl.api_keys.rotate(&request.id).await {
+        Ok((key, token)) => {
+            println!("Rotated API key {} ({})", key.id, key.name);
+            Ok(create_success_response(Some(
+                json!({ "api_key": key, "token": token }),
+            )))
+        }
+        Err(e) => Err(create_error_response(&format!(
+            "Unable to rotate API key: {}",
+            e
+        ))),
+    }
+}
+
+async fn handle_key_revoke(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Arc<Principal>>,
+    Json(request): Json<KeyIdRequest>,
+) -> ApiResult<SuccessResponse> {
+    require(&principal, Scope::Admin, None)?;
+
+    match state.acl.api_keys.revoke(&request.id).await {
+        Ok(_) => {
+            println!("Revoked API key {}", request.id);
+            Ok(create_success_response(None))
+        }
+        Err(e) => Err(create_error_response(&format!(
+            "Unable to revoke API key: {}",
+            e
+        ))),
+    }
+}
+
+fn content_type(kind: &Kind) -> &'static str {
+    match kind {
+        Kind::Number | Kind::Boolean | Kind::String => "text/plain; charset=utf-8",
+        Kind::Json | Kind::Object => "application/json",
+        Kind::Js => "text/javascript; charset=utf-8",
+        Kind::Blob => "application/octet-stream",
+    }
+}
+
+// Used when neither the header nor the query names the kind
+fn kind_from_content_type(headers: &HeaderMap) -> Option<Kind> {
+    let value = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+    let mime = value.split(';').next()?.trim();
+    match mime {
+        "text/plain" => Some(Kind::String),
+        "application/json" => Some(Kind::Json),
+        "text/javascript" | "application/javascript" => Some(Kind::Js),
+        "application/octet-stream" => Some(Kind::Blob),
+        _ => None,
+    }
+}
+
+fn etag(version: u64) -> HeaderValue {
+    HeaderValue::from_str(&format!("\"{}\"", version)).expect("Digits are a valid header")
+}
+
+// `If-Match: "<version>"` and `If-None-Match: *` map onto the SET conditions
+fn parse_http_condition(
+    headers: &HeaderMap,
+) -> Result<Option<Condition>, (StatusCode, ResponseJson<ErrorResponse>)> {
+    if let Some(value) = headers.get(header::IF_MATCH) {
+        let version = value
+            .to_str()
+            .ok()
+            .and_then(|value| value.trim().trim_matches('"').parse().ok())
+            .ok_or_else(|| create_error_response("Invalid If-Match, use the ETag of the key"))?;
+        return Ok(Some(Condition::Version(version)));
+    }
+    match headers.get(header::IF_NONE_MATCH) {
+        Some(value) if value == "*" => Ok(Some(Condition::Absent)),
+        Some(_) => Err(create_error_response("Only If-None-Match: * is supported")),
+        None => Ok(None),
+    }
+}
+
+async fn handle_key_get(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Arc<Principal>>,
+    Path(key): Path<String>,
+    Query(query): Query<KeyQuery>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, ResponseJson<ErrorResponse>)> {
+    require(&principal, Scope::Get, Some(&key))?;
+    let core = open_db(&state, query.db.as_deref())?;
+
+    let start = std::time::Instant::now();
+    let object = core.get_async(&key).await;
+    let duration = start.elapsed();
+    println!("GET /keys completed in {:.2?}", duration);
+
+    let Some(object) = object else {
+        let (_, error) = create_error_response("Not found");
+        return Err((StatusCode::NOT_FOUND, error));
+    };
+    let tag = etag(object.desc.version);
+    if headers.get(header::IF_NONE_MATCH) == Some(&tag) {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, tag)]).into_response());
+    }
+
+    let kind = object.desc.kind;
+    let body = match kind {
+        Kind::Number => {
+            let mut arr = [0u8; 8];
+            arr.copy_from_slice(&object.data[..8]);
+            f64::from_le_bytes(arr).to_string().into_bytes()
+        }
+        Kind::Boolean => (object.data[0] != 0).to_string().into_bytes(),
+        _ => object.data,
+    };
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static(content_type(&kind)),
+            ),
+            (header::ETAG, tag),
+            (
+                header::HeaderName::from_static(KIND_HEADER),
+                HeaderValue::from_str(&kind.to_string()).expect("Kind names are valid headers"),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+async fn handle_key_put(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Arc<Principal>>,
+    Path(key): Path<String>,
+    Query(query): Query<KeyQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, (StatusCode, ResponseJson<ErrorResponse>)> {
+    require(&principal, Scope::Set, Some(&key))?;
+    let core = open_db(&state, query.db.as_deref())?;
+    check_key(&key)?;
+
+    let named = headers
+        .get(KIND_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .or(query.kind);
+    let kind = match named {
+        Some(name) => Kind::from_str(&name).map_err(|_| create_error_response("Unknown kind"))?,
+        None => kind_from_content_type(&headers).ok_or_else(|| {
+            create_error_response("Pass the kind in the X-Humpback-Kind header or ?kind=")
+        })?,
+    };
+    let data = match kind {
+        Kind::Blob => body.to_vec(),
+        _ => {
+            let text = String::from_utf8(body.to_vec())
+                .map_err(|_| create_error_response("Value must be valid UTF-8"))?;
+            parse_set_data(&key, &kind.to_string(), text)?.1
+        }
+    };
+    let options = SetOptions {
+        ttl: parse_ttl(query.ttl)?,
+        condition: parse_http_condition(&headers)?,
+    };
+
+    let start = std::time::Instant::now();
+    let data_size = data.len();
+    let version = core
+        .set_async(&key, kind, data, options)
+        .await
+        .map_err(|e| create_error_response(&e.to_string()))?;
+    let duration = start.elapsed();
+    println!(
+        "PUT /keys completed in {:.2?} ({} bytes)",
+        duration, data_size
+    );
+
+    match version {
+        Some(version) => {
+            let mut response = create_success_response(None);
+            response.version = Some(version);
+            Ok(([(header::ETAG, etag(version))], response).into_response())
+        }
+        None => {
+            let (_, error) = create_error_response("Condition failed");
+            Err((StatusCode::PRECONDITION_FAILED, error))
+        }
+    }
+}
+
+async fn handle_key_delete(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Arc<Principal>>,
+    Path(key): Path<String>,
+    Query(query): Query<KeyQuery>,
+) -> ApiResult<SuccessResponse> {
+    require(&principal, Scope::Delete, Some(&key))?;
+    let core = open_db(&state, query.db.as_deref())?;
+
+    let start = std::time::Instant::now();
+    match core.delete_soft_async(&key).await {
+        Ok(_) => {
+            let duration = start.elapsed();
+            println!("DELETE /keys completed in {:.2?}", duration);
+            Ok(create_success_response(None))
+        }
+        Err(_) => {
+            let (_, error) = create_error_response("Not found");
+            Err((StatusCode::NOT_FOUND, error))
+        }
+    }
+}
+
+// One `bytes=` range as a half-open span of a value of `size` bytes,
+// `Ok(None)` serves the whole value. Malformed and multi-range headers are
+// ignored, which HTTP allows; `Err` means the range is past the end.
+fn parse_range(value: &HeaderValue, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(spec) = value
+        .to_str()
+        .ok()
+        .and_then(|value| value.trim().strip_prefix("bytes="))
+    else {
+        return Ok(None);
+    };
+    let Some((first, last)) = spec.split_once('-') else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let (start, end) = match (first.trim(), last.trim()) {
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => return Err(()),
+            Ok(length) => (size.saturating_sub(length), size),
+            Err(_) => return Ok(None),
+        },
+        (first, "") => match first.parse::<u64>() {
+            Ok(start) => (start, size),
+            Err(_) => return Ok(None),
+        },
+        (first, last) => match (first.parse::<u64>(), last.parse::<u64>()) {
+            (Ok(start), Ok(last)) if start <= last => (start, last.saturating_add(1).min(size)),
+            _ => return Ok(None),
+        },
+    };
+    if start >= size {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+fn upload_error(e: impl std::fmt::Display) -> (StatusCode, ResponseJson<ErrorResponse>) {
+    create_error_response(&format!("Upload failed: {}", e))
+}
+
+// Removes the staged file when dropped, also when the client disconnects
+// and the handler is dropped halfway through the upload
+struct StagedUpload(String);
+
+impl Drop for StagedUpload {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+// Writes the body to `path` as its chunks arrive and reopens it for reading
+async fn stage_upload(
+    path: &str,
+    body: Body,
+    max_size: u64,
+) -> Result<(std::fs::File, u64), (StatusCode, ResponseJson<ErrorResponse>)> {
+    let mut file = tokio::fs::File::create(path).await.map_err(upload_error)?;
+    let mut stream = body.into_data_stream();
+    let mut size = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(upload_error)?;
+        size += chunk.len() as u64;
+        if size > max_size {
+            let (_, error) = create_error_response(&format!(
+                "Blob is too large. Max blob size - {} bytes",
+                max_size
+            ));
+            return Err((StatusCode::PAYLOAD_TOO_LARGE, error));
+        }
+        file.write_all(&chunk).await.map_err(upload_error)?;
+    }
+    file.flush().await.map_err(upload_error)?;
+
+    let source = tokio::fs::File::open(path).await.map_err(upload_error)?;
+    Ok((source.into_std().await, size))
+}
+
+// Reads `start..end` of the value a chunk at a time on the blocking pool
+fn blob_stream(
+    reader: BlobReader,
+    start: u64,
+    end: u64,
+) -> impl Stream<Item = std::io::Result<Bytes>> {
+    let reader = Arc::new(reader);
+    futures::stream::try_unfold(start, move |pos| {
+        let reader = Arc::clone(&reader);
+        async move {
+            if pos >= end {
+                return Ok(None);
+            }
+            let len = BLOB_CHUNK_SIZE.min((end - pos) as usize);
+            let chunk = tokio::task::spawn_blocking(move || reader.read_chunk(pos, len))
+                .await
+                .expect("spawn_blocking failed")?;
+            Ok(Some((Bytes::from(chunk), pos + len as u64)))
+        }
+    })
+}
+
+async fn handle_blob_put(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Arc<Principal>>,
+    Path(key): Path<String>,
+    Query(query): Query<KeyQuery>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, (StatusCode, ResponseJson<ErrorResponse>)> {
+    require(&principal, Scope::Set, Some(&key))?;
+    check_key(&key)?;
+    let core = open_db(&state, query.db.as_deref())?;
+
+    if headers.contains_key(header::CONTENT_TYPE)
+        && kind_from_content_type(&headers) != Some(Kind::Blob)
+    {
+        let (_, error) = create_error_response("Blobs are uploaded as application/octet-stream");
+        return Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, error));
+    }
+    let options = SetOptions {
+        ttl: parse_ttl(query.ttl)?,
+        condition: parse_http_condition(&headers)?,
+    };
+
+    let start = std::time::Instant::now();
+    // Staged on disk first, so the write lock isn't held while the
+    // client is still sending
+    let upload = StagedUpload(core.upload_path());
+    let staged = stage_upload(&upload.0, body, state.max_blob_bytes).await;
+    // The open handle keeps the staged file readable after this
+    drop(upload);
+    let (source, size) = staged?;
+    let version = core
+        .set_blob_async(&key, source, options)
+        .await
+        .map_err(|e| create_error_response(&format!("Unable to store blob: {}", e)))?;
+    let duration = start.elapsed();
+    println!("PUT /blobs completed in {:.2?} ({} bytes)", duration, size);
+
+    match version {
+        Some(version) => {
+            let mut response = create_success_response(None);
+            response.version = Some(version);
+            Ok(([(header::ETAG, etag(version))], response).into_response())
+        }
+        None => {
+            let (_, error) = create_error_response("Condition failed");
+            Err((StatusCode::PRECONDITION_FAILED, error))
+        }
+    }
+}
+
+async fn handle_blob_get(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Arc<Principal>>,
+    Path(key): Path<String>,
+    Query(query): Query<KeyQuery>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, ResponseJson<ErrorResponse>)> {
+    require(&principal, Scope::Get, Some(&key))?;
+    let core = open_db(&state, query.db.as_deref())?;
+
+    let start = std::time::Instant::now();
+    let reader = core
+        .open_blob_async(&key)
+        .await
+        .map_err(|e| create_error_response(&format!("Unable to read blob: {}", e)))?;
+    let Some(reader) = reader else {
+        let (_, error) = create_error_response("Not found");
+        return Err((StatusCode::NOT_FOUND, error));
+    };
+    let tag = etag(reader.desc.version);
+    if headers.get(header::IF_NONE_MATCH) == Some(&tag) {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, tag)]).into_response());
+    }
+
+    let size = reader.size();
+    let kind = reader.desc.kind.clone();
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(content_type(&kind)),
+    );
+    response_headers.insert(header::ETAG, tag);
+    response_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    response_headers.insert(
+        header::HeaderName::from_static(KIND_HEADER),
+        HeaderValue::from_str(&kind.to_string()).expect("Kind names are valid headers"),
+    );
+
+    let range = match headers.get(header::RANGE) {
+        Some(value) => parse_range(value, size),
+        None => Ok(None),
+    };
+    let (status, from, to) = match range {
+        Ok(None) => (StatusCode::OK, 0, size),
+        Ok(Some((from, to))) => {
+            let content_range = format!("bytes {}-{}/{}", from, to - 1, size);
+            response_headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&content_range).expect("Digits are a valid header"),
+            );
+            (StatusCode::PARTIAL_CONTENT, from, to)
+        }
+        Err(()) => {
+            let content_range = format!("bytes */{}", size);
+            response_headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&content_range).expect("Digits are a valid header"),
+            );
+            return Ok((StatusCode::RANGE_NOT_SATISFIABLE, response_headers).into_response());
+        }
+    };
+    response_headers.insert(header::CONTENT_LENGTH, HeaderValue::from(to - from));
+
+    let duration = start.elapsed();
+    println!(
+        "GET /blobs opened in {:.2?} ({} of {} bytes)",
+        duration,
+        to - from,
+        size
+    );
+    let body = Body::from_stream(blob_stream(reader, from, to));
+    Ok((status, response_headers, body).into_response())
+}
+
+async fn handle_keys_list(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Arc<Principal>>,
+    Query(query): Query<KeysQuery>,
+) -> ApiResult<SuccessResponse> {
+    require(&principal, Scope::List, None)?;
+    let core = open_db(&state, query.db.as_deref())?;
+
+    let cursor = query
+        .cursor
+        .map(|cursor| ListCursor::from_str(&cursor).map_err(|e| create_error_response(&e)))
+        .transpose()?;
+    let options = ListOptions {
+        pattern: query.prefix.as_deref().map(KeyPattern::prefix),
+        cursor,
+        limit: query.limit,
+        key_prefixes: principal.key_prefixes().to_vec(),
+    };
+
+    let start = std::time::Instant::now();
+    let page = match query.kind {
+        Some(kind) => {
+            let kind = Kind::from_str(&kind).map_err(|_| create_error_response("Invalid type"))?;
+            core.list_by_kind(kind, options).await
+        }
+        None => core.list(options).await,
+    };
+    match page {
+        Ok(page) => {
+            let items: Vec<ListItem> = page
+                .items
+                .iter()
+                .map(|element| ListItem {
+                    key: element.key.clone(),
+                    kind: element.kind.to_string(),
+                    size: element.size as usize,
+                    version: element.version,
+                })
+                .collect();
+
+            let duration = start.elapsed();
+            println!("GET /keys completed in {:.2?}", duration);
+
+            let mut response = create_success_response(Some(
+                serde_json::to_value(items).unwrap_or(serde_json::Value::Array(vec![])),
+            ));
+            response.cursor = page.cursor.map(|cursor| cursor.to_string());
+            Ok(response)
+        }
+        Err(_) => Err(create_error_response("Unable to list objects")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(header: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+        parse_range(&HeaderValue::from_str(header).unwrap(), size)
+    }
+
+    #[test]
+    fn parse_range_spans() {
+        assert_eq!(range("bytes=0-99", 1000), Ok(Some((0, 100))));
+        assert_eq!(range("bytes=5-", 1000), Ok(Some((5, 1000))));
+        assert_eq!(range("bytes=-100", 1000), Ok(Some((900, 1000))));
+        assert_eq!(range("bytes=-5000", 1000), Ok(Some((0, 1000))));
+        // The end is clamped to the value
+        assert_eq!(range("bytes=990-2000", 1000), Ok(Some((990, 1000))));
+    }
+
+    #[test]
+    fn parse_range_past_the_end() {
+        assert_eq!(range("bytes=1000-", 1000), Err(()));
+        assert_eq!(range("bytes=1000-1100", 1000), Err(()));
+        assert_eq!(range("bytes=-0", 1000), Err(()));
+        assert_eq!(range("bytes=-5", 0), Err(()));
+    }
+
+    #[test]
+    fn parse_range_ignores_what_it_can_not_serve() {
+        assert_eq!(range("items=0-99", 1000), Ok(None));
+        assert_eq!(range("bytes=abc", 1000), Ok(None));
+        assert_eq!(range("bytes=5-1", 1000), Ok(None));
+        assert_eq!(range("bytes=0-1,5-9", 1000), Ok(None));
+        assert_eq!(range("bytes=x-9", 1000), Ok(None));
+    }
+}
@@ -1,18 +1,61 @@
-use crate::kv;
-use crate::kv::objects::Kind;
+use crate::kv::core::{Condition, Core, SetOptions};
+use crate::kv::databases::Databases;
+use crate::kv::expiry;
+use crate::kv::objects::{Kind, ListCursor, ListFilter, ScanRange};
+use crate::kv::pattern::KeyPattern;
 use deno_core::OpState;
 use deno_core::error::AnyError;
 use deno_core::op2;
 use deno_core::serde_json::{self, json};
+use serde::Deserialize;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
+
+// Every kv op gets the database name picked with `kv.db(name)` in JS
+fn open_db(state: &mut OpState, db: &str) -> Result<Arc<Core>, AnyError> {
+    let dbs = state.borrow::<Arc<Databases>>().clone();
+    dbs.get(Some(db)).map_err(|e| AnyError::msg(e.to_string()))
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct JsSetOptions {
+    ttl: Option<u64>,
+    if_version: Option<u64>,
+    if_absent: bool,
+    if_present: bool,
+}
+
+// A ttl of 0 means no expiry
+fn set_options(options: JsSetOptions) -> Result<SetOptions, AnyError> {
+    let condition = match (options.if_version, options.if_absent, options.if_present) {
+        (None, false, false) => None,
+        (Some(version), false, false) => Some(Condition::Version(version)),
+        (None, true, false) => Some(Condition::Absent),
+        (None, false, true) => Some(Condition::Present),
+        _ => {
+            return Err(AnyError::msg(
+                "Use only one of ifVersion, ifAbsent and ifPresent",
+            ));
+        }
+    };
+    let ttl = options
+        .ttl
+        .filter(|ttl| *ttl > 0)
+        .map(|secs| expiry::check_ttl(Duration::from_secs(secs)).map_err(AnyError::msg))
+        .transpose()?;
+    Ok(SetOptions { ttl, condition })
+}
 
 #[op2]
 #[serde]
 pub fn op_kv_get_value(
     state: &mut OpState,
+    #[string] db: String,
     #[string] key: String,
 ) -> Result<serde_json::Value, AnyError> {
-    let core = state.borrow::<Arc<kv::core::Core>>().clone();
+    let core = open_db(state, &db)?;
     let value = core.get(&key);
 
     Ok(match value {
@@ -36,8 +79,12 @@ pub fn op_kv_get_value(
 }
 #[op2]
 #[string]
-pub fn op_kv_get_kind(state: &mut OpState, #[string] key: String) -> Result<String, AnyError> {
-    let core = state.borrow::<Arc<kv::core::Core>>().clone();
+pub fn op_kv_get_kind(
+    state: &mut OpState,
+    #[string] db: String,
+    #[string] key: String,
+) -> Result<String, AnyError> {
+    let core = open_db(state, &db)?;
     let value = core.get(&key);
 
     Ok(match value {
@@ -45,6 +92,16 @@ pub fn op_kv_get_kind(state: &mut OpState, #[string] key: String) -> Result<Stri
         None => "null".to_string(),
     })
 }
+#[op2]
+#[serde]
+pub fn op_kv_get_version(
+    state: &mut OpState,
+    #[string] db: String,
+    #[string] key: String,
+) -> Result<serde_json::Value, AnyError> {
+    let core = open_db(state, &db)?;
+    Ok(json!(core.version(&key)))
+}
 // #[op2]
 // #[serde]
 // pub fn op_kv_get_value(
@@ -74,48 +131,172 @@ pub fn op_kv_get_kind(state: &mut OpState, #[string] key: String) -> Result<Stri
 //     })
 // }
 
-#[op2(fast)]
+#[op2]
+#[serde]
 pub fn op_kv_set_string(
     state: &mut OpState,
+    #[string] db: String,
     #[string] key: String,
     #[string] data: String,
-) -> Result<(), AnyError> {
-    let core = state.borrow::<Arc<kv::core::Core>>().clone();
+    #[serde] options: JsSetOptions,
+) -> Result<serde_json::Value, AnyError> {
+    let core = open_db(state, &db)?;
     let data_bytes = data.into_bytes();
-    core.set(&key, Kind::String, data_bytes);
-    Ok(())
+    let version = core
+        .set(&key, Kind::String, data_bytes, set_options(options)?)
+        .map_err(|e| AnyError::msg(e.to_string()))?;
+    Ok(json!(version))
+}
+
+#[op2(fast)]
+pub fn op_kv_incr(
+    state: &mut OpState,
+    #[string] db: String,
+    #[string] key: String,
+    delta: f64,
+) -> Result<f64, AnyError> {
+    let core = open_db(state, &db)?;
+    core.incr(&key, delta)
+        .map_err(|e| AnyError::msg(e.to_string()))
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct JsScanOptions {
+    prefix: Option<String>,
+    start: Option<String>,
+    end: Option<String>,
+    limit: Option<usize>,
+}
+
+#[op2]
+#[serde]
+pub fn op_kv_scan(
+    state: &mut OpState,
+    #[string] db: String,
+    #[serde] options: JsScanOptions,
+) -> Result<serde_json::Value, AnyError> {
+    let core = open_db(state, &db)?;
+    let range = match options.prefix {
+        Some(prefix) => ScanRange::Prefix(prefix),
+        None => ScanRange::Range {
+            start: options.start.unwrap_or_default(),
+            end: options.end,
+        },
+    };
+    let list = core
+        .scan(range, options.limit, &[])
+        .map_err(|e| AnyError::msg(e.to_string()))?;
+    Ok(json!(
+        list.iter()
+            .map(|element| json!({
+                "key": element.key,
+                "kind": element.kind.to_string(),
+                "size": element.size,
+                "version": element.version,
+            }))
+            .collect::<Vec<_>>()
+    ))
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct JsListOptions {
+    pattern: Option<String>,
+    regex: bool,
+    kind: Option<String>,
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+#[op2]
+#[serde]
+pub fn op_kv_list(
+    state: &mut OpState,
+    #[string] db: String,
+    #[serde] options: JsListOptions,
+) -> Result<serde_json::Value, AnyError> {
+    let core = open_db(state, &db)?;
+    let pattern = match options.pattern {
+        Some(pattern) if options.regex => Some(KeyPattern::regex(&pattern).map_err(AnyError::msg)?),
+        Some(pattern) => Some(KeyPattern::glob(&pattern)),
+        None => None,
+    };
+    let cursor = options
+        .cursor
+        .map(|cursor| ListCursor::from_str(&cursor).map_err(AnyError::msg))
+        .transpose()?;
+    let kind = options
+        .kind
+        .map(|kind| Kind::from_str(&kind).map_err(|_| AnyError::msg("Unknown kind")))
+        .transpose()?;
+    let filter = ListFilter {
+        kind: kind.as_ref(),
+        pattern: pattern.as_ref(),
+        ..Default::default()
+    };
+    let page = core
+        .objects
+        .list(&filter, cursor.as_ref(), options.limit)
+        .map_err(|e| AnyError::msg(e.to_string()))?;
+    Ok(json!({
+        "items": page
+            .items
+            .iter()
+            .map(|element| json!({
+                "key": element.key,
+                "kind": element.kind.to_string(),
+                "size": element.size,
+                "version": element.version,
+            }))
+            .collect::<Vec<_>>(),
+        "cursor": page.cursor.map(|cursor| cursor.to_string()),
+    }))
 }
 
 #[op2(fast)]
-pub fn op_kv_delete(state: &mut OpState, #[string] key: String) -> Result<(), AnyError> {
-    let core = state.borrow::<Arc<kv::core::Core>>().clone();
+pub fn op_kv_delete(
+    state: &mut OpState,
+    #[string] db: String,
+    #[string] key: String,
+) -> Result<(), AnyError> {
+    let core = open_db(state, &db)?;
     // TO DO
     // core.delete_soft_async(&key).await;
     Ok(())
 }
 
-#[op2(fast)]
+#[op2]
+#[serde]
 pub fn op_kv_set_number(
     state: &mut OpState,
+    #[string] db: String,
     #[string] key: String,
     data: f64,
-) -> Result<(), AnyError> {
-    let core = state.borrow::<Arc<kv::core::Core>>().clone();
+    #[serde] options: JsSetOptions,
+) -> Result<serde_json::Value, AnyError> {
+    let core = open_db(state, &db)?;
     println!("{}", data);
     let data_bytes = data.to_le_bytes().to_vec();
-    core.set(&key, Kind::Number, data_bytes);
-    Ok(())
+    let version = core
+        .set(&key, Kind::Number, data_bytes, set_options(options)?)
+        .map_err(|e| AnyError::msg(e.to_string()))?;
+    Ok(json!(version))
 }
 
 #[op2]
 #[serde]
 pub fn op_kv_set_object(
     state: &mut OpState,
+    #[string] db: String,
     #[string] key: String,
     #[serde] data: serde_json::Value,
-) -> Result<(), AnyError> {
-    let core = state.borrow::<Arc<kv::core::Core>>().clone();
+    #[serde] options: JsSetOptions,
+) -> Result<serde_json::Value, AnyError> {
+    let core = open_db(state, &db)?;
     let bytes = serde_json::to_vec(&data).unwrap();
-    core.set(&key, Kind::Object, bytes);
-    Ok(())
+    let version = core
+        .set(&key, Kind::Object, bytes, set_options(options)?)
+        .map_err(|e| AnyError::msg(e.to_string()))?;
+    Ok(json!(version))
 }
@@ -25,10 +25,11 @@ globalThis.file = {
   },
 };
 
-globalThis.kv = {
+// Operations on one named database, `kv` itself works on "main"
+const openDb = (db) => ({
   get: (key) => {
-    const kind = core.ops.op_kv_get_kind(key);
-    const value = core.ops.op_kv_get_value(key);
+    const kind = core.ops.op_kv_get_kind(db, key);
+    const value = core.ops.op_kv_get_value(db, key);
     if (kind == "object") {
       const parsed = JSON.parse(value);
       return parsed;
@@ -36,23 +37,49 @@ globalThis.kv = {
     return value;
   },
 
+  version: (key) => {
+    return core.ops.op_kv_get_version(db, key);
+  },
+
   delete: (key) => {
-    return core.ops.op_kv_delete(key);
+    return core.ops.op_kv_delete(db, key);
   },
 
-  set: (key, data) => {
+  incr: (key, delta = 1) => {
+    return core.ops.op_kv_incr(db, key, delta);
+  },
+
+  // options: { pattern, regex, kind, cursor, limit }, returns
+  // { items, cursor } where cursor is null on the last page
+  list: (options = {}) => {
+    return core.ops.op_kv_list(db, options);
+  },
+
+  // options: { prefix } or { start, end }, plus an optional limit
+  scan: (options = {}) => {
+    return core.ops.op_kv_scan(db, options);
+  },
+
+  // Returns the new version, or null when ifVersion / ifAbsent /
+  // ifPresent in options did not hold
+  set: (key, data, options = {}) => {
     const type = typeof data;
     core.print(type);
     if (type === "string") {
-      return core.ops.op_kv_set_string(key, data);
+      return core.ops.op_kv_set_string(db, key, data, options);
     }
     if (type === "number") {
-      return core.ops.op_kv_set_number(key, data);
+      return core.ops.op_kv_set_number(db, key, data, options);
     }
     if (type === "object") {
-      return core.ops.op_kv_set_object(key, data);
+      return core.ops.op_kv_set_object(db, key, data, options);
     }
   },
+});
+
+globalThis.kv = {
+  ...openDb("main"),
+  db: (name) => openDb(name),
 };
 
 globalThis.http = {
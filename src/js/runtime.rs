@@ -8,21 +8,26 @@ use std::rc::Rc;
 use std::sync::Arc;
 use std::sync::Mutex;
 
+use crate::config::JsConfig;
 use crate::js::event::Event;
 use crate::js::op_event;
 use crate::js::op_http;
 use crate::js::op_kv;
 use crate::kv;
-use kv::core::Core;
+use kv::databases::Databases;
 use std::thread;
 extension!(
   runjs,
   ops = [
     op_kv::op_kv_get_value,
     op_kv::op_kv_get_kind,
+    op_kv::op_kv_get_version,
     op_kv::op_kv_set_string,
     op_kv::op_kv_set_number,
     op_kv::op_kv_set_object,
+    op_kv::op_kv_incr,
+    op_kv::op_kv_scan,
+    op_kv::op_kv_list,
     op_kv::op_kv_delete,
     op_http::op_http_get,
     // op_http::op_http_post,
@@ -42,10 +47,15 @@ pub struct Runtime {
     results: Results,
 }
 impl Runtime {
-    pub fn new(core: Arc<Core>) -> Arc<Self> {
+    pub fn new(dbs: Arc<Databases>, config: &JsConfig) -> Arc<Self> {
         let events: Events = Arc::new(Mutex::new(VecDeque::new()));
         let results: Results = Arc::new(Mutex::new(HashMap::new()));
-        spawn_js_runtime(Arc::clone(&core), Arc::clone(&events), Arc::clone(&results));
+        spawn_js_runtime(
+            Arc::clone(&dbs),
+            config.event_loop.clone(),
+            Arc::clone(&events),
+            Arc::clone(&results),
+        );
         Arc::new(Runtime { events, results })
     }
 
@@ -64,7 +74,7 @@ impl Runtime {
         rx
     }
 }
-fn spawn_js_runtime(core: Arc<Core>, events: Events, results: Results) {
+fn spawn_js_runtime(dbs: Arc<Databases>, event_loop: String, events: Events, results: Results) {
     thread::spawn(move || {
         let rt = tokio::runtime::Builder::new_current_thread()
             .enable_all()
@@ -74,7 +84,7 @@ fn spawn_js_runtime(core: Arc<Core>, events: Events, results: Results) {
         loop {
             let res: Result<(), ()> = rt.block_on(async {
                 let main_module =
-                    deno_core::resolve_path("./eventLoop.js", &std::env::current_dir().unwrap())
+                    deno_core::resolve_path(&event_loop, &std::env::current_dir().unwrap())
                         .unwrap();
 
                 let mut js_runtime = deno_core::JsRuntime::new(deno_core::RuntimeOptions {
@@ -86,7 +96,7 @@ fn spawn_js_runtime(core: Arc<Core>, events: Events, results: Results) {
                 {
                     let op_state = js_runtime.op_state();
                     let mut op_state = op_state.borrow_mut();
-                    op_state.put::<Arc<Core>>(Arc::clone(&core));
+                    op_state.put::<Arc<Databases>>(Arc::clone(&dbs));
                     op_state.put::<Events>(Arc::clone(&events));
                     op_state.put::<Results>(Arc::clone(&results));
                 }
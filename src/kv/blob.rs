@@ -0,0 +1,63 @@
+use std::{error::Error, fs::File, os::unix::fs::FileExt, sync::Mutex};
+
+use crate::kv::{io_service as io, objects::ObjectDescriptor};
+
+/// Reads a stored value in pieces, for values too large to load at once.
+/// It holds its own handle on the data file, so a compaction that renames
+/// a new file into place doesn't move the bytes under it.
+pub struct BlobReader {
+    pub desc: ObjectDescriptor,
+    file: File,
+    checksum: u32,
+    // Position and crc32 while the value is read front to back
+    progress: Mutex<Option<(u64, crc32fast::Hasher)>>,
+}
+
+impl BlobReader {
+    /// Expects `file` to be the data file `desc` points into.
+    pub fn open(
+        file: File,
+        desc: ObjectDescriptor,
+    ) -> Result<BlobReader, Box<dyn Error + Send + Sync>> {
+        let checksum = io::read_header(&file, desc.offset, desc.size)?;
+        Ok(BlobReader {
+            desc,
+            file,
+            checksum,
+            progress: Mutex::new(Some((0, crc32fast::Hasher::new()))),
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.desc.size
+    }
+
+    /// Reads at most `len` bytes starting `pos` bytes into the value. When
+    /// the whole value is read in order, the chunk that ends it fails if the
+    /// payload doesn't match its checksum.
+    pub fn read_chunk(&self, pos: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        let len = len.min(self.size().saturating_sub(pos) as usize);
+        let mut chunk = vec![0u8; len];
+        self.file
+            .read_exact_at(&mut chunk, self.desc.offset + io::HEADER_SIZE + pos)?;
+
+        let mut progress = self.progress.lock().unwrap();
+        match progress.as_mut() {
+            Some((next, hasher)) if *next == pos => {
+                hasher.update(&chunk);
+                *next += len as u64;
+                if *next == self.size()
+                    && let Some((_, hasher)) = progress.take()
+                    && hasher.finalize() != self.checksum
+                {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Checksum mismatch at offset {}", self.desc.offset),
+                    ));
+                }
+            }
+            _ => *progress = None,
+        }
+        Ok(chunk)
+    }
+}
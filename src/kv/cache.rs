@@ -0,0 +1,62 @@
+use std::sync::Mutex;
+
+use lru::LruCache;
+
+struct Entries {
+    values: LruCache<u64, Vec<u8>>,
+    used: usize,
+}
+
+/// Payloads read from the data file, keyed by their record offset and
+/// bounded by the total number of payload bytes.
+pub struct Cache {
+    pub budget: usize,
+    entries: Mutex<Entries>,
+}
+
+impl Cache {
+    pub fn new(budget: usize) -> Cache {
+        Cache {
+            budget,
+            entries: Mutex::new(Entries {
+                values: LruCache::unbounded(),
+                used: 0,
+            }),
+        }
+    }
+
+    pub fn get(&self, offset: u64) -> Option<Vec<u8>> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.values.get(&offset).cloned()
+    }
+
+    pub fn insert(&self, offset: u64, data: Vec<u8>) {
+        if data.len() > self.budget {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.used += data.len();
+        if let Some(old) = entries.values.put(offset, data) {
+            entries.used -= old.len();
+        }
+        while entries.used > self.budget {
+            match entries.values.pop_lru() {
+                Some((_, evicted)) => entries.used -= evicted.len(),
+                None => break,
+            }
+        }
+    }
+
+    pub fn remove(&self, offset: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(old) = entries.values.pop(&offset) {
+            entries.used -= old.len();
+        }
+    }
+
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.values.clear();
+        entries.used = 0;
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    error::Error,
+    fs::{self, File},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::kv::{
+    core::Core,
+    io_service as io,
+    objects::{Kind, ListFilter, ObjectDescriptor},
+};
+
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(60);
+// Background compaction only kicks in once the data file is this big
+// and at least this fraction of it is garbage.
+const MIN_DATA_FILE_SIZE: u64 = 16 * 1024 * 1024;
+const MIN_GARBAGE_RATIO: f64 = 0.5;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CompactionStats {
+    pub live_objects: usize,
+    pub data_bytes_before: u64,
+    pub data_bytes_after: u64,
+    pub desc_bytes_before: u64,
+    pub desc_bytes_after: u64,
+}
+
+fn compact_filename(path: &str) -> String {
+    format!("{}.compact", path)
+}
+
+// Written once both compacted files are complete and synced, so a restart
+// knows whether to finish the swap or throw the leftovers away.
+fn marker_filename(dir: &str, prefix: &str) -> String {
+    format!("{}/{}.compact.done", dir, prefix)
+}
+
+fn sync_dir(dir: &str) -> std::io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+pub fn recover(dir: &str, prefix: &str) -> std::io::Result<()> {
+    let paths = [
+        io::get_data_filename(dir, prefix),
+        io::get_desc_filename(dir, prefix),
+    ];
+    let marker = marker_filename(dir, prefix);
+
+    if Path::new(&marker).exists() {
+        for path in &paths {
+            let compacted = compact_filename(path);
+            if Path::new(&compacted).exists() {
+                fs::rename(&compacted, path)?;
+            }
+        }
+        fs::remove_file(&marker)?;
+        sync_dir(dir)?;
+        println!("Finished interrupted compaction of '{}'", prefix);
+        return Ok(());
+    }
+
+    for path in &paths {
+        let compacted = compact_filename(path);
+        if Path::new(&compacted).exists() {
+            fs::remove_file(&compacted)?;
+            println!("Discarded incomplete compaction file {}", compacted);
+        }
+    }
+    Ok(())
+}
+
+pub fn compact(core: &Core) -> Result<CompactionStats, Box<dyn Error + Send + Sync>> {
+    let _guard = core.write_lock.lock().unwrap();
+
+    let data_path = io::get_data_filename(&core.data_dir, &core.name);
+    let desc_path = io::get_desc_filename(&core.data_dir, &core.name);
+    let data_compacted = compact_filename(&data_path);
+    let desc_compacted = compact_filename(&desc_path);
+    let marker = marker_filename(&core.data_dir, &core.name);
+
+    let data_bytes_before = fs::metadata(&data_path)?.len();
+    let desc_bytes_before = fs::metadata(&desc_path)?.len();
+
+    let new_data_file = Arc::new(Mutex::new(io::create_data_file(&data_compacted)?));
+    let new_desc_file = Arc::new(Mutex::new(io::create_desc_file(&desc_compacted)?));
+
+    // Versions of deleted keys are not copied over, so the counter has to
+    // be, or it would go back and hand out a version twice after a restart
+    let version_marker = ObjectDescriptor::version_marker(core.last_version());
+    io::save_desc_in_file(
+        bincode::serialize(&version_marker)?,
+        Arc::clone(&new_desc_file),
+    )?;
+
+    // Readers only need the map, so they keep going while live objects
+    // are copied into the new files.
+    let mut moved = Vec::new();
+    {
+        let map = core
+            .objects
+            .objects_map
+            .read()
+            .map_err(|e| format!("Poisoned lock: {}", e))?;
+        for (key, object) in map.iter() {
+            let loaded;
+            // Blobs are never resident, see `Core::set_blob`
+            let data = match core.cache {
+                None if object.desc.kind != Kind::Blob => &object.data,
+                _ => {
+                    loaded = core.data_map.read(
+                        &core.data_file,
+                        object.desc.offset,
+                        object.desc.size,
+                    )?;
+                    &loaded
+                }
+            };
+            let offset = io::save_object_in_file(data, Arc::clone(&new_data_file))?;
+            let mut desc = object.desc.clone();
+            desc.offset = offset;
+            desc.txn_id = 0;
+            let desc_offset =
+                io::save_desc_in_file(bincode::serialize(&desc)?, Arc::clone(&new_desc_file))?;
+            moved.push((key.clone(), offset, desc_offset));
+        }
+    }
+    new_data_file.lock().unwrap().sync_all()?;
+    new_desc_file.lock().unwrap().sync_all()?;
+
+    // Lazy readers hold the map read lock while reading the data file, so
+    // swapping files and offsets under the write lock keeps them consistent.
+    let mut map = core
+        .objects
+        .objects_map
+        .write()
+        .map_err(|e| format!("Poisoned lock: {}", e))?;
+
+    File::create(&marker)?.sync_all()?;
+    sync_dir(&core.data_dir)?;
+    fs::rename(&data_compacted, &data_path)?;
+    fs::rename(&desc_compacted, &desc_path)?;
+    fs::remove_file(&marker)?;
+    sync_dir(&core.data_dir)?;
+
+    let data_file = io::open_data_file(&data_path)?;
+    core.data_map.remap(&data_file)?;
+    *core.data_file.lock().unwrap() = data_file;
+    *core.desc_file.lock().unwrap() = io::open_desc_file(&desc_path)?;
+    let live_objects = moved.len();
+    for (key, offset, desc_offset) in moved {
+        if let Some(object) = map.get_mut(&key) {
+            object.desc.offset = offset;
+            object.desc.desc_offset = desc_offset;
+            // The commit markers are gone, so a later in-place rewrite of
+            // this record must not name a transaction either
+            object.desc.txn_id = 0;
+        }
+    }
+    if let Some(cache) = &core.cache {
+        cache.clear();
+    }
+    drop(map);
+
+    Ok(CompactionStats {
+        live_objects,
+        data_bytes_before,
+        data_bytes_after: fs::metadata(&data_path)?.len(),
+        desc_bytes_before,
+        desc_bytes_after: fs::metadata(&desc_path)?.len(),
+    })
+}
+
+fn needs_compaction(core: &Core) -> bool {
+    let data_size = match core.data_file.lock().unwrap().metadata() {
+        Ok(meta) => meta.len(),
+        Err(_) => return false,
+    };
+    if data_size < MIN_DATA_FILE_SIZE {
+        return false;
+    }
+    let live_size: u64 = match core.objects.list(&ListFilter::default(), None, None) {
+        Ok(page) => page.items.iter().map(|e| io::HEADER_SIZE + e.size).sum(),
+        Err(_) => return false,
+    };
+    let garbage_ratio = 1.0 - live_size as f64 / data_size as f64;
+    garbage_ratio >= MIN_GARBAGE_RATIO
+}
+
+pub fn spawn_background(core: &Arc<Core>) {
+    let core = Arc::downgrade(core);
+    thread::spawn(move || {
+        loop {
+            thread::sleep(COMPACTION_INTERVAL);
+            let Some(core) = core.upgrade() else {
+                break;
+            };
+            if !needs_compaction(&core) {
+                continue;
+            }
+            match compact(&core) {
+                Ok(stats) => println!(
+                    "Compaction of '{}' done: {} objects, data {} -> {} bytes",
+                    core.name, stats.live_objects, stats.data_bytes_before, stats.data_bytes_after
+                ),
+                Err(e) => eprintln!("Compaction of '{}' failed: {}", core.name, e),
+            }
+        }
+    });
+}
@@ -1,190 +1,1003 @@
 use std::{
+    collections::HashMap,
     error::Error,
-    fs::{self, File, OpenOptions},
-    path::Path,
-    sync::{Arc, Mutex},
+    fs::{self, File},
+    io::{Seek, SeekFrom},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
 };
 
-use crate::{
-    DIR_PATH,
-    kv::{
-        io_service as io,
-        objects::{self, Key256, Kind, Object, ObjectDescriptor, ObjectListElement},
+use crate::kv::{
+    blob::BlobReader,
+    cache::Cache,
+    compaction,
+    durability::{Durability, Syncer},
+    expiry, io_service as io, migration,
+    mmap::MappedFile,
+    objects::{
+        self, Key256, Kind, ListCursor, ListFilter, ListPage, MAX_KEY_LEN, Object,
+        ObjectDescriptor, ObjectListElement, ScanRange,
     },
+    pattern::KeyPattern,
+    recovery,
+    transaction::{Transaction, TxnOp},
 };
 
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Directory holding the data and descriptor files of every database.
+    pub data_dir: String,
+    pub durability: Durability,
+    /// Keep only descriptors resident and read values on demand through
+    /// an LRU cache holding at most this many bytes.
+    pub cache_bytes: Option<usize>,
+}
+
+/// Precondition for a SET, checked under the write lock.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Condition {
+    /// The key exists with exactly this version.
+    Version(u64),
+    Absent,
+    Present,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ListOptions {
+    pub pattern: Option<KeyPattern>,
+    pub cursor: Option<ListCursor>,
+    pub limit: Option<usize>,
+    /// Keys under one of these prefixes, every key when empty
+    pub key_prefixes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SetOptions {
+    pub ttl: Option<Duration>,
+    pub condition: Option<Condition>,
+}
+
 pub struct Core {
+    pub name: String,
+    pub data_dir: String,
     pub objects: objects::ObjectService,
     pub data_file: Arc<Mutex<File>>,
     pub desc_file: Arc<Mutex<File>>,
+    // Serializes every change of the files and the map, so compaction
+    // never observes a half-written object.
+    pub write_lock: Mutex<()>,
+    pub syncer: Arc<Syncer>,
+    pub cache: Option<Cache>,
+    pub data_map: MappedFile,
+    next_txn_id: AtomicU64,
+    next_version: AtomicU64,
 }
 impl Core {
-    pub fn new() -> Result<Arc<Core>, std::io::Error> {
-        fs::create_dir_all(DIR_PATH).expect("Unable to create directory with data...");
-        let data_file_path = io::get_data_filename("main");
-        if !Path::new(&data_file_path).exists() {
-            File::create(&data_file_path)?;
-        }
-
-        let data_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .append(true)
-            .create(true)
-            .open(&data_file_path)?;
-
-        let desc_file_path = io::get_desc_filename("main");
-        if !Path::new(&desc_file_path).exists() {
-            File::create(&desc_file_path)?;
-        }
-
-        let desc_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .append(false)
-            .create(true)
-            .open(&desc_file_path)?;
-        let mut core = Core {
-            objects: objects::ObjectService::new(),
-            data_file: Arc::new(Mutex::new(data_file)),
-            desc_file: Arc::new(Mutex::new(desc_file)),
+    /// Opens the database stored in `{name}.Data.bindb` / `{name}.Desc.bindb`.
+    pub fn new(name: &str, options: Options) -> Result<Arc<Core>, std::io::Error> {
+        let data_dir = options.data_dir.clone();
+        fs::create_dir_all(&data_dir).expect("Unable to create directory with data...");
+        let name = name.to_string();
+        compaction::recover(&data_dir, &name)?;
+        migration::upgrade_legacy(&data_dir, &name)?;
+        io::remove_stale_uploads(&data_dir, &name)?;
+
+        let mut data_file = io::open_data_file(&io::get_data_filename(&data_dir, &name))?;
+        let mut desc_file = io::open_desc_file(&io::get_desc_filename(&data_dir, &name))?;
+        recovery::truncate_torn_tails(&mut data_file, &mut desc_file)?;
+        let data_map = MappedFile::new(&data_file)?;
+
+        let data_file = Arc::new(Mutex::new(data_file));
+        let desc_file = Arc::new(Mutex::new(desc_file));
+
+        let mut objects = objects::ObjectService::new();
+        let log_state = objects.load_objects_desc(Arc::clone(&desc_file));
+        let cache = match options.cache_bytes {
+            Some(budget) => {
+                println!("Lazy loading enabled, value cache budget: {} bytes", budget);
+                Some(Cache::new(budget))
+            }
+            None => {
+                objects.load_objects_data(Arc::clone(&data_file));
+                None
+            }
         };
-        core.objects.load_objects_desc(Arc::clone(&core.desc_file));
-        core.objects.load_objects_data(Arc::clone(&core.data_file));
 
-        Ok(Arc::new(core))
+        let syncer = Syncer::new(
+            options.durability,
+            Arc::clone(&data_file),
+            Arc::clone(&desc_file),
+        );
+        let core = Arc::new(Core {
+            name,
+            data_dir,
+            objects,
+            data_file,
+            desc_file,
+            write_lock: Mutex::new(()),
+            syncer,
+            cache,
+            data_map,
+            next_txn_id: AtomicU64::new(log_state.last_txn_id + 1),
+            next_version: AtomicU64::new(log_state.last_version + 1),
+        });
+        compaction::spawn_background(&core);
+        expiry::spawn_reaper(&core);
+
+        Ok(core)
     }
 
-    pub async fn get_async(&self, key: &str) -> Option<Object> {
-        return self.objects.get_object(key);
+    pub async fn get_async(self: &Arc<Self>, key: &str) -> Option<Object> {
+        if self.cache.is_none() {
+            match self.objects.get_object(key) {
+                Some(object) if object.desc.kind == Kind::Blob => {}
+                object => return object,
+            }
+        }
+        let core = Arc::clone(self);
+        let key_owned = key.to_string();
+        tokio::task::spawn_blocking(move || core.get(&key_owned))
+            .await
+            .expect("spawn_blocking failed")
     }
+    /// Like `read`, but a value that can't be read is logged and reported
+    /// as missing.
     pub fn get(&self, key: &str) -> Option<Object> {
-        return self.objects.get_object(key);
+        self.read(key).unwrap_or_else(|e| {
+            eprintln!("Failed to read value of '{}': {}", key, e);
+            None
+        })
+    }
+    /// `Ok(None)` for a missing key, `Err` when the value is there but can't
+    /// be read from disk.
+    pub fn read(&self, key: &str) -> Result<Option<Object>, Box<dyn Error + Send + Sync>> {
+        if self.cache.is_none() {
+            match self.objects.get_object(key) {
+                Some(object) if object.desc.kind == Kind::Blob => {}
+                object => return Ok(object),
+            }
+        }
+        // The map stays read-locked while loading, so compaction can't move
+        // the record meanwhile
+        self.objects
+            .with_desc(key, |desc| {
+                // Blobs aren't cached, they would push out everything else
+                let cache = self.cache.as_ref().filter(|_| desc.kind != Kind::Blob);
+                let data = match cache.and_then(|cache| cache.get(desc.offset)) {
+                    Some(data) => data,
+                    None => {
+                        let data = self
+                            .data_map
+                            .read(&self.data_file, desc.offset, desc.size)?;
+                        if let Some(cache) = cache {
+                            cache.insert(desc.offset, data.clone());
+                        }
+                        data
+                    }
+                };
+                Ok(Object {
+                    desc: desc.clone(),
+                    data,
+                })
+            })
+            .transpose()
     }
 
-    pub async fn set_async(&self, key: &str, kind: Kind, mut data: Vec<u8>) {
-        match kind {
-            Kind::Number => {
-                if data.len() < 8 {
-                    data.resize(8, 0);
-                } else if data.len() > 8 {
-                    data.truncate(8);
-                }
-                let mut arr = [0u8; 8];
-                arr.copy_from_slice(&data);
-                let number = f64::from_le_bytes(arr);
-                if !number.is_finite() {
-                    eprintln!("Invalid number")
-                }
-                println!("{}", number);
-                data = number.to_le_bytes().to_vec();
+    pub async fn set_async(
+        self: &Arc<Self>,
+        key: &str,
+        kind: Kind,
+        data: Vec<u8>,
+        options: SetOptions,
+    ) -> Result<Option<u64>, Box<dyn Error + Send + Sync>> {
+        let core = Arc::clone(self);
+        let key_owned = key.to_string();
+        tokio::task::spawn_blocking(move || core.set(&key_owned, kind, data, options))
+            .await
+            .expect("spawn_blocking failed")
+    }
+    /// Returns the version of the written value, or `None` when the
+    /// condition in `options` did not hold and nothing was written.
+    pub fn set(
+        &self,
+        key: &str,
+        kind: Kind,
+        data: Vec<u8>,
+        options: SetOptions,
+    ) -> Result<Option<u64>, Box<dyn Error + Send + Sync>> {
+        check_key(key)?;
+        let data = normalize_data(&kind, data);
+        let guard = self.write_lock.lock().unwrap();
+        if !self.condition_holds(key, options.condition) {
+            return Ok(None);
+        }
+
+        let desc = self.append_object(key, kind, &data, expiry::expires_at(options.ttl), 0)?;
+
+        let version = desc.version;
+        let ticket = self.syncer.written()?;
+        self.objects
+            .set(self.resident(desc, data))
+            .map_err(|e| e.to_string())?;
+        drop(guard);
+
+        self.syncer.wait(ticket)?;
+        Ok(Some(version))
+    }
+    /// A fresh path to stage an upload at before `set_blob`, next to the
+    /// data file so both are on the same disk.
+    pub fn upload_path(&self) -> String {
+        io::get_upload_filename(
+            &self.data_dir,
+            &self.name,
+            &uuid::Uuid::new_v4().to_string(),
+        )
+    }
+    pub async fn set_blob_async(
+        self: &Arc<Self>,
+        key: &str,
+        source: File,
+        options: SetOptions,
+    ) -> Result<Option<u64>, Box<dyn Error + Send + Sync>> {
+        let core = Arc::clone(self);
+        let key_owned = key.to_string();
+        tokio::task::spawn_blocking(move || core.set_blob(&key_owned, source, options))
+            .await
+            .expect("spawn_blocking failed")
+    }
+    /// Stores the rest of `source` as a blob, copied into the data file in
+    /// chunks. Returns like `set`. Blobs are never kept in memory, not even
+    /// in eager mode; reads load them from the data file.
+    pub fn set_blob(
+        &self,
+        key: &str,
+        mut source: File,
+        options: SetOptions,
+    ) -> Result<Option<u64>, Box<dyn Error + Send + Sync>> {
+        check_key(key)?;
+        // Checksummed up front so the write lock is only held for the copy
+        let start = source.stream_position()?;
+        let (size, checksum) = io::checksum_reader(&mut source)?;
+        source.seek(SeekFrom::Start(start))?;
+
+        let guard = self.write_lock.lock().unwrap();
+        if !self.condition_holds(key, options.condition) {
+            return Ok(None);
+        }
+        let offset =
+            io::save_object_from_reader(&mut source, size, checksum, Arc::clone(&self.data_file))?;
+        let desc = self.append_record_desc(
+            key,
+            Kind::Blob,
+            offset,
+            size,
+            expiry::expires_at(options.ttl),
+            0,
+        )?;
+        let version = desc.version;
+        let ticket = self.syncer.written()?;
+        self.objects
+            .set(Object { desc, data: vec![] })
+            .map_err(|e| e.to_string())?;
+        drop(guard);
+
+        self.syncer.wait(ticket)?;
+        Ok(Some(version))
+    }
+    pub async fn open_blob_async(
+        self: &Arc<Self>,
+        key: &str,
+    ) -> Result<Option<BlobReader>, Box<dyn Error + Send + Sync>> {
+        let core = Arc::clone(self);
+        let key_owned = key.to_string();
+        tokio::task::spawn_blocking(move || core.open_blob(&key_owned))
+            .await
+            .expect("spawn_blocking failed")
+    }
+    /// Opens the value of `key` for reading in chunks, whatever its kind.
+    pub fn open_blob(&self, key: &str) -> Result<Option<BlobReader>, Box<dyn Error + Send + Sync>> {
+        // Under the map lock the descriptor and the data file can't be
+        // swapped by a compaction in between
+        let opened = self.objects.with_desc(key, |desc| {
+            let file = self.data_file.lock().unwrap().try_clone()?;
+            Ok::<_, std::io::Error>((file, desc.clone()))
+        });
+        match opened {
+            Some(opened) => {
+                let (file, desc) = opened?;
+                Ok(Some(BlobReader::open(file, desc)?))
             }
-            _ => {}
+            None => Ok(None),
+        }
+    }
+    fn condition_holds(&self, key: &str, condition: Option<Condition>) -> bool {
+        let current = self.objects.get_desc(key).map(|desc| desc.version);
+        match condition {
+            None => true,
+            Some(Condition::Absent) => current.is_none(),
+            Some(Condition::Present) => current.is_some(),
+            Some(Condition::Version(version)) => current == Some(version),
         }
-        let data_file = Arc::clone(&self.data_file);
-        let desc_file = Arc::clone(&self.desc_file);
+    }
+    /// The newest version handed out, 0 before the first write.
+    pub fn last_version(&self) -> u64 {
+        self.next_version.load(Ordering::Relaxed) - 1
+    }
+    pub fn version(&self, key: &str) -> Option<u64> {
+        self.objects.get_desc(key).map(|desc| desc.version)
+    }
+    pub async fn incr_async(
+        self: &Arc<Self>,
+        key: &str,
+        delta: f64,
+    ) -> Result<f64, Box<dyn Error + Send + Sync>> {
+        let core = Arc::clone(self);
         let key_owned = key.to_string();
-        let kind_clone = kind.clone();
-        let size = data.len();
-        let data_clone = data.clone();
-
-        let obj = tokio::task::spawn_blocking(move || {
-            let offset = io::save_object_in_file(&data_clone, data_file)
-                .expect("Failed to write data") as u64;
-
-            let mut desc = ObjectDescriptor {
-                key: Key256::new(&key_owned),
-                kind: kind_clone.clone(),
-                offset,
-                size: size as u64,
-                is_deleted: false,
-                desc_offset: 0,
-            };
-
-            let desc_data = bincode::serialize(&desc).unwrap();
-
-            let desc_offset =
-                io::save_desc_in_file(desc_data, desc_file).expect("Failed to write descriptor");
-            desc.desc_offset = desc_offset;
-
-            Object { desc, data }
-        })
-        .await
-        .expect("spawn_blocking failed");
-
-        self.objects.set(obj).unwrap();
-    }
-    pub fn set(&self, key: &str, kind: Kind, mut data: Vec<u8>) {
-        match kind {
-            Kind::Number => {
-                if data.len() < 8 {
-                    data.resize(8, 0);
-                } else if data.len() > 8 {
-                    data.truncate(8);
+        tokio::task::spawn_blocking(move || core.incr(&key_owned, delta))
+            .await
+            .expect("spawn_blocking failed")
+    }
+    /// Adds `delta` to a number and returns the result. A missing key
+    /// starts from 0; an existing TTL is kept. A string holding a number,
+    /// as text protocols like RESP set it, counts as that number.
+    pub fn incr(&self, key: &str, delta: f64) -> Result<f64, Box<dyn Error + Send + Sync>> {
+        check_key(key)?;
+        if !delta.is_finite() {
+            return Err("Invalid increment".into());
+        }
+        let guard = self.write_lock.lock().unwrap();
+
+        let (current, expires_at) = match self.read(key)? {
+            Some(object) => {
+                let current = match object.desc.kind {
+                    Kind::Number => f64::from_le_bytes(object.data[..8].try_into()?),
+                    Kind::String => std::str::from_utf8(&object.data)
+                        .ok()
+                        .and_then(|text| text.parse::<f64>().ok())
+                        .filter(|number| number.is_finite())
+                        .ok_or("Value is not a number")?,
+                    _ => return Err("Value is not a number".into()),
+                };
+                (current, object.desc.expires_at)
+            }
+            None => (0.0, 0),
+        };
+        let number = current + delta;
+        if !number.is_finite() {
+            return Err("Increment would overflow".into());
+        }
+        let data = number.to_le_bytes().to_vec();
+        let desc = self.append_object(key, Kind::Number, &data, expires_at, 0)?;
+        let ticket = self.syncer.written()?;
+        self.objects
+            .set(self.resident(desc, data))
+            .map_err(|e| e.to_string())?;
+        drop(guard);
+
+        self.syncer.wait(ticket)?;
+        Ok(number)
+    }
+    pub async fn commit_async(
+        self: &Arc<Self>,
+        txn: Transaction,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let core = Arc::clone(self);
+        tokio::task::spawn_blocking(move || core.commit(txn))
+            .await
+            .expect("spawn_blocking failed")
+    }
+    /// Appends every staged record tagged with a fresh transaction id, then
+    /// the commit marker. Readers see the whole batch at once, and recovery
+    /// drops records whose marker never made it to disk.
+    pub fn commit(&self, txn: Transaction) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if txn.is_empty() {
+            return Ok(());
+        }
+        for op in &txn.ops {
+            if let TxnOp::Set { key, .. } = op {
+                check_key(key)?;
+            }
+        }
+        let guard = self.write_lock.lock().unwrap();
+        for op in &txn.ops {
+            if let TxnOp::Set { key, options, .. } = op
+                && !self.condition_holds(key, options.condition)
+            {
+                return Err(format!("Condition failed for key '{}'", key).into());
+            }
+        }
+        let txn_id = self.next_txn_id.fetch_add(1, Ordering::Relaxed);
+
+        let mut staged: HashMap<String, Option<Object>> = HashMap::new();
+        let mut order = Vec::new();
+        for op in txn.ops {
+            match op {
+                TxnOp::Set {
+                    key,
+                    kind,
+                    data,
+                    options,
+                } => {
+                    let data = normalize_data(&kind, data);
+                    let desc = self.append_object(
+                        &key,
+                        kind,
+                        &data,
+                        expiry::expires_at(options.ttl),
+                        txn_id,
+                    )?;
+                    order.push(key.clone());
+                    staged.insert(key, Some(self.resident(desc, data)));
                 }
-                let mut arr = [0u8; 8];
-                arr.copy_from_slice(&data);
-                let number = f64::from_le_bytes(arr);
-                if !number.is_finite() {
-                    eprintln!("Invalid number")
+                TxnOp::Delete { key } => {
+                    let current = match staged.get(&key) {
+                        Some(object) => object.as_ref().map(|o| o.desc.clone()),
+                        None => self.objects.get_desc(&key),
+                    };
+                    let Some(mut desc) = current else {
+                        continue;
+                    };
+                    desc.is_deleted = true;
+                    desc.txn_id = txn_id;
+                    self.append_desc(&mut desc)?;
+                    order.push(key.clone());
+                    staged.insert(key, None);
                 }
-                println!("{}", number);
-                data = number.to_le_bytes().to_vec();
             }
-            _ => {}
         }
-        let data_file = Arc::clone(&self.data_file);
-        let desc_file = Arc::clone(&self.desc_file);
-        let key_owned = key.to_string();
-        let kind_clone = kind.clone();
-        let size = data.len();
-        let data_clone = data.clone();
-        let offset =
-            io::save_object_in_file(&data_clone, data_file).expect("Failed to write data") as u64;
+        self.append_desc(&mut ObjectDescriptor::commit_marker(txn_id))?;
+        let ticket = self.syncer.written()?;
 
+        let mut batch = Vec::with_capacity(staged.len());
+        for key in order {
+            if let Some(object) = staged.remove(&key) {
+                if object.is_none()
+                    && let Some(cache) = &self.cache
+                    && let Some(desc) = self.objects.get_desc(&key)
+                {
+                    cache.remove(desc.offset);
+                }
+                batch.push((key, object));
+            }
+        }
+        self.objects.apply(batch)?;
+        drop(guard);
+
+        self.syncer.wait(ticket)?;
+        Ok(())
+    }
+    fn append_object(
+        &self,
+        key: &str,
+        kind: Kind,
+        data: &Vec<u8>,
+        expires_at: u64,
+        txn_id: u64,
+    ) -> Result<ObjectDescriptor, Box<dyn Error + Send + Sync>> {
+        let offset = io::save_object_in_file(data, Arc::clone(&self.data_file))?;
+        self.append_record_desc(key, kind, offset, data.len() as u64, expires_at, txn_id)
+    }
+    // Describes a record already appended to the data file
+    fn append_record_desc(
+        &self,
+        key: &str,
+        kind: Kind,
+        offset: u64,
+        size: u64,
+        expires_at: u64,
+        txn_id: u64,
+    ) -> Result<ObjectDescriptor, Box<dyn Error + Send + Sync>> {
         let mut desc = ObjectDescriptor {
-            key: Key256::new(&key_owned),
-            kind: kind_clone.clone(),
+            key: Key256::new(key),
+            kind,
             offset,
-            size: size as u64,
+            size,
             is_deleted: false,
+            expires_at,
+            version: self.next_version.fetch_add(1, Ordering::Relaxed),
+            txn_id,
+            is_commit: false,
             desc_offset: 0,
         };
+        self.append_desc(&mut desc)?;
+        Ok(desc)
+    }
+    fn append_desc(&self, desc: &mut ObjectDescriptor) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let desc_data = bincode::serialize(&desc)?;
+        desc.desc_offset = io::save_desc_in_file(desc_data, Arc::clone(&self.desc_file))?;
+        Ok(())
+    }
+    // In lazy mode the payload goes to the cache instead of the map.
+    fn resident(&self, desc: ObjectDescriptor, data: Vec<u8>) -> Object {
+        let data = match &self.cache {
+            Some(cache) => {
+                cache.insert(desc.offset, data);
+                vec![]
+            }
+            None => data,
+        };
+        Object { desc, data }
+    }
+    /// Remaining time to live: `None` for a missing key, `Some(None)` for
+    /// a key without expiry.
+    pub fn ttl(&self, key: &str) -> Option<Option<Duration>> {
+        let desc = self.objects.get_desc(key)?;
+        if desc.expires_at == 0 {
+            return Some(None);
+        }
+        let remaining = desc.expires_at.saturating_sub(expiry::now_millis());
+        Some(Some(Duration::from_millis(remaining)))
+    }
+    /// Sets a new TTL on an existing key, `None` makes it persistent.
+    pub fn set_expiry(
+        &self,
+        key: &str,
+        ttl: Option<Duration>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let guard = self.write_lock.lock().unwrap();
+
+        let mut desc = self.objects.get_desc(key).ok_or("Object not found")?;
+        desc.expires_at = expiry::expires_at(ttl);
+        // A standalone record; keeping the id of the transaction that wrote
+        // the value would leave it waiting for a commit marker on replay
+        desc.txn_id = 0;
+        desc.is_commit = false;
+        self.append_desc(&mut desc)?;
+        let ticket = self.syncer.written()?;
+        self.objects.update_desc(key, desc)?;
+        drop(guard);
+
+        self.syncer.wait(ticket)?;
+        Ok(())
+    }
+    pub async fn set_expiry_async(
+        self: &Arc<Self>,
+        key: &str,
+        ttl: Option<Duration>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let core = Arc::clone(self);
+        let key_owned = key.to_string();
+        tokio::task::spawn_blocking(move || core.set_expiry(&key_owned, ttl))
+            .await
+            .expect("spawn_blocking failed")
+    }
+    pub async fn delete_soft_async(
+        self: &Arc<Self>,
+        key: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let core = Arc::clone(self);
+        let key_owned = key.to_string();
+        tokio::task::spawn_blocking(move || core.delete_soft(&key_owned))
+            .await
+            .expect("spawn_blocking failed")
+    }
+    pub fn delete_soft(&self, key: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let guard = self.write_lock.lock().unwrap();
+
+        if self.objects.get_desc(key).is_none() {
+            return Err("Object not found".into());
+        }
+        self.delete_locked(key)?;
+        let ticket = self.syncer.written()?;
+        drop(guard);
+
+        self.syncer.wait(ticket)?;
+        Ok(())
+    }
+    pub async fn get_many_async(self: &Arc<Self>, keys: Vec<String>) -> Vec<Option<Object>> {
+        let core = Arc::clone(self);
+        tokio::task::spawn_blocking(move || core.get_many(&keys))
+            .await
+            .expect("spawn_blocking failed")
+    }
+    pub fn get_many(&self, keys: &[String]) -> Vec<Option<Object>> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+    pub async fn set_many_async(
+        self: &Arc<Self>,
+        entries: Vec<(String, Kind, Vec<u8>)>,
+    ) -> Result<Vec<u64>, Box<dyn Error + Send + Sync>> {
+        let core = Arc::clone(self);
+        tokio::task::spawn_blocking(move || core.set_many(entries))
+            .await
+            .expect("spawn_blocking failed")
+    }
+    /// Appends every entry under one lock acquisition and waits for a single
+    /// sync. Unlike `commit` the writes are independent: after a crash any
+    /// prefix of them may survive. Returns the versions in entry order.
+    pub fn set_many(
+        &self,
+        entries: Vec<(String, Kind, Vec<u8>)>,
+    ) -> Result<Vec<u64>, Box<dyn Error + Send + Sync>> {
+        if entries.is_empty() {
+            return Ok(Vec::new());
+        }
+        for (key, _, _) in &entries {
+            check_key(key)?;
+        }
+        let guard = self.write_lock.lock().unwrap();
+
+        let mut versions = Vec::with_capacity(entries.len());
+        let mut batch = Vec::with_capacity(entries.len());
+        for (key, kind, data) in entries {
+            let data = normalize_data(&kind, data);
+            let desc = self.append_object(&key, kind, &data, 0, 0)?;
+            versions.push(desc.version);
+            batch.push((key, Some(self.resident(desc, data))));
+        }
+        let ticket = self.syncer.written()?;
+        self.objects.apply(batch)?;
+        drop(guard);
+
+        self.syncer.wait(ticket)?;
+        Ok(versions)
+    }
+    pub async fn delete_many_async(
+        self: &Arc<Self>,
+        keys: Vec<String>,
+    ) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let core = Arc::clone(self);
+        tokio::task::spawn_blocking(move || core.delete_many(&keys))
+            .await
+            .expect("spawn_blocking failed")
+    }
+    /// Deletes the keys that exist under one lock acquisition and a single
+    /// sync, returns how many were deleted.
+    pub fn delete_many(&self, keys: &[String]) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let guard = self.write_lock.lock().unwrap();
 
-        let desc_data = bincode::serialize(&desc).unwrap();
+        let mut deleted = 0;
+        for key in keys {
+            if self.objects.get_desc(key).is_some() {
+                self.delete_locked(key)?;
+                deleted += 1;
+            }
+        }
+        if deleted == 0 {
+            return Ok(0);
+        }
+        let ticket = self.syncer.written()?;
+        drop(guard);
 
-        let desc_offset =
-            io::save_desc_in_file(desc_data, desc_file).expect("Failed to write descriptor");
-        desc.desc_offset = desc_offset;
+        self.syncer.wait(ticket)?;
+        Ok(deleted)
+    }
+    /// Used by the reaper; re-checks under the write lock so a key that was
+    /// set again in the meantime survives.
+    pub fn delete_expired(&self, key: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let guard = self.write_lock.lock().unwrap();
+
+        if !self.objects.is_expired(key, expiry::now_millis()) {
+            return Ok(());
+        }
+        self.delete_locked(key)?;
+        let ticket = self.syncer.written()?;
+        drop(guard);
 
-        self.objects.set(Object { desc, data }).unwrap();
+        self.syncer.wait(ticket)?;
+        Ok(())
     }
-    pub async fn delete_soft_async(&self, key: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    fn delete_locked(&self, key: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
         let mut object = self
             .objects
             .delete(key.to_string())
             .map_err(|e| format!("Failed to mark object as deleted: {}", e))?;
+        if let Some(cache) = &self.cache {
+            cache.remove(object.desc.offset);
+        }
         object.desc.is_deleted = true;
         let data =
             bincode::serialize(&object.desc).map_err(|e| format!("Serialization error: {}", e))?;
 
-        let desc_file: Arc<Mutex<File>> = Arc::clone(&self.desc_file);
-
-        tokio::task::spawn_blocking(move || {
-            io::update_chunk_in_file(object.desc.desc_offset, data, desc_file)
-        })
-        .await
-        .expect("spawn_blocking failed")
-        .expect("Failed to write data");
+        io::update_desc_in_file(object.desc.desc_offset, data, Arc::clone(&self.desc_file))?;
         Ok(())
     }
-    pub async fn list(&self) -> Result<Vec<ObjectListElement>, Box<dyn Error + Send + Sync>> {
-        return self.objects.list();
+    pub fn sync(&self) -> std::io::Result<()> {
+        self.syncer.sync_files()
+    }
+    pub async fn compact_async(
+        self: &Arc<Self>,
+    ) -> Result<compaction::CompactionStats, Box<dyn Error + Send + Sync>> {
+        let core = Arc::clone(self);
+        tokio::task::spawn_blocking(move || compaction::compact(&core))
+            .await
+            .expect("spawn_blocking failed")
+    }
+    /// Lists keys matching `options.pattern` in write order. Without a
+    /// limit everything after the cursor is returned.
+    pub async fn list(
+        &self,
+        options: ListOptions,
+    ) -> Result<ListPage, Box<dyn Error + Send + Sync>> {
+        let filter = ListFilter {
+            pattern: options.pattern.as_ref(),
+            key_prefixes: &options.key_prefixes,
+            ..Default::default()
+        };
+        self.objects
+            .list(&filter, options.cursor.as_ref(), options.limit)
     }
     pub async fn list_by_kind(
         &self,
         kind: Kind,
+        options: ListOptions,
+    ) -> Result<ListPage, Box<dyn Error + Send + Sync>> {
+        let filter = ListFilter {
+            kind: Some(&kind),
+            pattern: options.pattern.as_ref(),
+            key_prefixes: &options.key_prefixes,
+        };
+        self.objects
+            .list(&filter, options.cursor.as_ref(), options.limit)
+    }
+    pub fn scan(
+        &self,
+        range: ScanRange,
+        limit: Option<usize>,
+        key_prefixes: &[String],
     ) -> Result<Vec<ObjectListElement>, Box<dyn Error + Send + Sync>> {
-        return self.objects.list_by_kind(kind);
+        self.objects.scan(&range, limit, key_prefixes)
+    }
+}
+
+// Longer keys would be cut in their descriptor, possibly inside a character
+fn check_key(key: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if key.len() > MAX_KEY_LEN {
+        return Err(format!("Key is too long. Max key length - {} bytes", MAX_KEY_LEN).into());
+    }
+    Ok(())
+}
+
+fn normalize_data(kind: &Kind, mut data: Vec<u8>) -> Vec<u8> {
+    match kind {
+        Kind::Number => {
+            if data.len() < 8 {
+                data.resize(8, 0);
+            } else if data.len() > 8 {
+                data.truncate(8);
+            }
+            let mut arr = [0u8; 8];
+            arr.copy_from_slice(&data);
+            let number = f64::from_le_bytes(arr);
+            if !number.is_finite() {
+                eprintln!("Invalid number")
+            }
+            println!("{}", number);
+            number.to_le_bytes().to_vec()
+        }
+        _ => data,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(dir: &tempfile::TempDir, cache_bytes: Option<usize>) -> Arc<Core> {
+        let options = Options {
+            data_dir: dir.path().to_str().unwrap().to_string(),
+            durability: Durability::Os,
+            cache_bytes,
+        };
+        Core::new("test", options).unwrap()
+    }
+
+    fn set(core: &Core, key: &str, value: &str) {
+        core.set(key, Kind::String, value.into(), SetOptions::default())
+            .unwrap()
+            .unwrap();
+    }
+
+    fn value(core: &Core, key: &str) -> Option<String> {
+        core.get(key)
+            .map(|object| String::from_utf8(object.data).unwrap())
+    }
+
+    fn restart_keeps_newest(cache_bytes: Option<usize>) {
+        let dir = tempfile::tempdir().unwrap();
+        let core = open(&dir, cache_bytes);
+        set(&core, "overwritten", "first");
+        set(&core, "overwritten", "second");
+        set(&core, "deleted", "value");
+        core.delete_soft("deleted").unwrap();
+        set(&core, "reset", "before");
+        core.delete_soft("reset").unwrap();
+        set(&core, "reset", "after");
+        drop(core);
+
+        let core = open(&dir, cache_bytes);
+        assert_eq!(value(&core, "overwritten").as_deref(), Some("second"));
+        assert_eq!(value(&core, "deleted"), None);
+        assert_eq!(value(&core, "reset").as_deref(), Some("after"));
+    }
+
+    #[test]
+    fn restart_keeps_newest_write() {
+        restart_keeps_newest(None);
+    }
+
+    #[test]
+    fn restart_keeps_newest_write_lazy() {
+        restart_keeps_newest(Some(1024));
+    }
+
+    #[test]
+    fn versions_keep_increasing_after_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let core = open(&dir, None);
+        set(&core, "a", "1");
+        set(&core, "a", "2");
+        let before = core.version("a").unwrap();
+        drop(core);
+
+        let core = open(&dir, None);
+        assert_eq!(core.version("a"), Some(before));
+        set(&core, "b", "1");
+        assert!(core.version("b").unwrap() > before);
+    }
+
+    #[test]
+    fn long_keys_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let core = open(&dir, None);
+        let longest = "k".repeat(MAX_KEY_LEN);
+        let long = "k".repeat(MAX_KEY_LEN + 1);
+
+        assert!(
+            core.set(&longest, Kind::String, "v".into(), SetOptions::default())
+                .is_ok()
+        );
+        assert!(
+            core.set(&long, Kind::String, "v".into(), SetOptions::default())
+                .is_err()
+        );
+        assert!(core.incr(&long, 1.0).is_err());
+        assert!(
+            core.set_many(vec![
+                ("ok".to_string(), Kind::String, "v".into()),
+                (long.clone(), Kind::String, "v".into()),
+            ])
+            .is_err()
+        );
+        let mut txn = Transaction::new();
+        txn.set(&long, Kind::String, "v".into(), SetOptions::default());
+        assert!(core.commit(txn).is_err());
+        // Nothing of a rejected batch is written
+        assert_eq!(value(&core, "ok"), None);
+        assert_eq!(core.last_version(), 1);
+    }
+
+    #[test]
+    fn incr_accepts_numeric_strings() {
+        let dir = tempfile::tempdir().unwrap();
+        let core = open(&dir, None);
+        set(&core, "counter", "10");
+        set(&core, "text", "ten");
+
+        assert_eq!(core.incr("counter", 1.0).unwrap(), 11.0);
+        assert_eq!(core.get("counter").unwrap().desc.kind, Kind::Number);
+        assert!(core.incr("text", 1.0).is_err());
+        assert_eq!(value(&core, "text").as_deref(), Some("ten"));
+    }
+
+    #[test]
+    fn incr_fails_on_an_unreadable_value() {
+        let dir = tempfile::tempdir().unwrap();
+        let core = open(&dir, Some(1024));
+        core.incr("counter", 5.0).unwrap();
+        let offset = core.objects.get_desc("counter").unwrap().offset;
+        drop(core);
+
+        let path = io::get_data_filename(dir.path().to_str().unwrap(), "test");
+        let file = fs::OpenOptions::new().write(true).open(path).unwrap();
+        std::os::unix::fs::FileExt::write_all_at(&file, &[0xff], offset + io::HEADER_SIZE).unwrap();
+
+        let core = open(&dir, Some(1024));
+        assert!(core.incr("counter", 1.0).is_err());
+        assert!(core.read("counter").is_err());
+        assert!(core.read("missing").unwrap().is_none());
+    }
+
+    #[test]
+    fn blobs_are_not_resident_in_eager_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let core = open(&dir, None);
+        let path = dir.path().join("source");
+        fs::write(&path, b"blob contents").unwrap();
+        core.set_blob("blob", File::open(&path).unwrap(), SetOptions::default())
+            .unwrap();
+        let resident = |core: &Core| core.objects.get_object("blob").unwrap().data;
+
+        assert!(resident(&core).is_empty());
+        assert_eq!(value(&core, "blob").as_deref(), Some("blob contents"));
+        compaction::compact(&core).unwrap();
+        assert_eq!(value(&core, "blob").as_deref(), Some("blob contents"));
+        drop(core);
+
+        let core = open(&dir, None);
+        assert!(resident(&core).is_empty());
+        assert_eq!(value(&core, "blob").as_deref(), Some("blob contents"));
+    }
+
+    fn commit_set(core: &Core, key: &str, value: &str) {
+        let mut txn = Transaction::new();
+        txn.set(key, Kind::String, value.into(), SetOptions::default());
+        core.commit(txn).unwrap();
+    }
+
+    #[test]
+    fn committed_transaction_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let core = open(&dir, None);
+        commit_set(&core, "a", "committed");
+        drop(core);
+
+        let core = open(&dir, None);
+        assert_eq!(value(&core, "a").as_deref(), Some("committed"));
+    }
+
+    #[test]
+    fn records_without_commit_marker_are_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let core = open(&dir, None);
+        set(&core, "a", "before");
+        // A transaction that crashed before writing its marker
+        core.append_object("a", Kind::String, &b"uncommitted".to_vec(), 0, 99)
+            .unwrap();
+        drop(core);
+
+        let core = open(&dir, None);
+        assert_eq!(value(&core, "a").as_deref(), Some("before"));
+    }
+
+    #[test]
+    fn expiry_of_transaction_value_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let core = open(&dir, None);
+        commit_set(&core, "a", "value");
+        core.set_expiry("a", Some(Duration::from_secs(3600)))
+            .unwrap();
+        drop(core);
+
+        let core = open(&dir, None);
+        assert!(matches!(core.ttl("a"), Some(Some(_))));
+    }
+
+    #[test]
+    fn compaction_clears_transaction_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let core = open(&dir, None);
+        commit_set(&core, "a", "value");
+        assert_ne!(core.objects.get_desc("a").unwrap().txn_id, 0);
+
+        compaction::compact(&core).unwrap();
+        assert_eq!(core.objects.get_desc("a").unwrap().txn_id, 0);
+        core.set_expiry("a", Some(Duration::from_secs(3600)))
+            .unwrap();
+        drop(core);
+
+        let core = open(&dir, None);
+        assert_eq!(value(&core, "a").as_deref(), Some("value"));
+        assert!(matches!(core.ttl("a"), Some(Some(_))));
+    }
+
+    #[test]
+    fn versions_of_compacted_deletes_are_not_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let core = open(&dir, None);
+        set(&core, "kept", "1");
+        set(&core, "deleted", "1");
+        let newest = core.version("deleted").unwrap();
+        core.delete_soft("deleted").unwrap();
+        compaction::compact(&core).unwrap();
+        drop(core);
+
+        let core = open(&dir, None);
+        assert_eq!(core.last_version(), newest);
+        set(&core, "new", "1");
+        assert!(core.version("new").unwrap() > newest);
     }
 }
@@ -0,0 +1,160 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use crate::kv::core::{Core, Options};
+
+pub const DEFAULT_DB: &str = "main";
+/// Server metadata such as API keys, out of reach of clients
+pub const SYSTEM_DB: &str = "_system";
+const MAX_NAME_LEN: usize = 64;
+
+/// Named databases, each with its own files and object map. Every database
+/// but `main` is opened on first use and stays open afterwards.
+pub struct Databases {
+    options: Options,
+    /// Counts every database but the system one
+    max_opened: usize,
+    opened: RwLock<HashMap<String, Arc<Core>>>,
+    // Held while a database is opened, so replaying its log doesn't block
+    // lookups and no two threads open the same files
+    opening: Mutex<()>,
+}
+
+impl Databases {
+    pub fn new(options: Options, max_opened: usize) -> Result<Arc<Databases>, std::io::Error> {
+        let main = Core::new(DEFAULT_DB, options.clone())?;
+        let mut opened = HashMap::new();
+        opened.insert(DEFAULT_DB.to_string(), main);
+        Ok(Arc::new(Databases {
+            options,
+            max_opened,
+            opened: RwLock::new(opened),
+            opening: Mutex::new(()),
+        }))
+    }
+
+    pub fn main(&self) -> Arc<Core> {
+        Arc::clone(&self.opened.read().unwrap()[DEFAULT_DB])
+    }
+
+    /// Returns the database `name`, opening it if needed. `None` is `main`.
+    pub fn get(&self, name: Option<&str>) -> Result<Arc<Core>, Box<dyn Error + Send + Sync>> {
+        let name = name.unwrap_or(DEFAULT_DB);
+        if name.starts_with('_') {
+            return Err(format!("Database name '{}' is reserved", name).into());
+        }
+        if !is_valid_name(name) {
+            return Err(format!(
+                "Invalid database name '{}'. Use up to {} letters, digits, '_' or '-'",
+                name, MAX_NAME_LEN
+            )
+            .into());
+        }
+        self.open(name)
+    }
+
+    /// The internal database, which `get` refuses to hand out.
+    pub fn system(&self) -> Result<Arc<Core>, Box<dyn Error + Send + Sync>> {
+        self.open(SYSTEM_DB)
+    }
+
+    fn open(&self, name: &str) -> Result<Arc<Core>, Box<dyn Error + Send + Sync>> {
+        if let Some(core) = self.opened.read().unwrap().get(name) {
+            return Ok(Arc::clone(core));
+        }
+
+        let _opening = self.opening.lock().unwrap();
+        // Another request may have opened it while we waited for the lock
+        if let Some(core) = self.opened.read().unwrap().get(name) {
+            return Ok(Arc::clone(core));
+        }
+        if name != SYSTEM_DB {
+            let count = self
+                .opened
+                .read()
+                .unwrap()
+                .keys()
+                .filter(|name| *name != SYSTEM_DB)
+                .count();
+            if count >= self.max_opened {
+                return Err(format!(
+                    "Unable to open database '{}', at most {} can be open",
+                    name, self.max_opened
+                )
+                .into());
+            }
+        }
+        let core = Core::new(name, self.options.clone())?;
+        println!("Opened database '{}'", name);
+        self.opened
+            .write()
+            .unwrap()
+            .insert(name.to_string(), Arc::clone(&core));
+        Ok(core)
+    }
+
+    pub fn sync(&self) -> std::io::Result<()> {
+        for core in self.opened.read().unwrap().values() {
+            core.sync()?;
+        }
+        Ok(())
+    }
+}
+
+// Names end up in file names, so keep them to a safe character set without
+// path separators or dots
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::durability::Durability;
+
+    fn databases(dir: &tempfile::TempDir, max_opened: usize) -> Arc<Databases> {
+        let options = Options {
+            data_dir: dir.path().to_str().unwrap().to_string(),
+            durability: Durability::Os,
+            cache_bytes: None,
+        };
+        Databases::new(options, max_opened).unwrap()
+    }
+
+    #[test]
+    fn names_are_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let dbs = databases(&dir, 4);
+        for name in [
+            "",
+            "../main",
+            "a/b",
+            "a\\b",
+            "a.b",
+            "_system",
+            &"x".repeat(65),
+        ] {
+            assert!(dbs.get(Some(name)).is_err(), "{:?} was accepted", name);
+        }
+        assert!(dbs.get(Some("tenant-1_a")).is_ok());
+    }
+
+    #[test]
+    fn open_databases_are_limited() {
+        let dir = tempfile::tempdir().unwrap();
+        let dbs = databases(&dir, 2);
+        dbs.system().unwrap();
+        let first = dbs.get(Some("first")).unwrap();
+        assert!(dbs.get(Some("second")).is_err());
+        // Already open ones are still handed out
+        assert!(Arc::ptr_eq(&dbs.get(Some("first")).unwrap(), &first));
+        assert!(dbs.get(None).is_ok());
+    }
+}
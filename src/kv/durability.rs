@@ -0,0 +1,266 @@
+use std::{
+    fmt,
+    fs::File,
+    str::FromStr,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+const DEFAULT_GROUP_COMMIT_MS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Durability {
+    /// fsync after every SET/DELETE before acknowledging it.
+    #[default]
+    Always,
+    /// Writers wait for a shared fsync issued every interval.
+    GroupCommit(Duration),
+    /// Leave flushing to the OS page cache.
+    Os,
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_lowercase();
+        match s.split_once(':') {
+            None if s == "always" => Ok(Durability::Always),
+            None if s == "os" => Ok(Durability::Os),
+            None if s == "group" => Ok(Durability::GroupCommit(Duration::from_millis(
+                DEFAULT_GROUP_COMMIT_MS,
+            ))),
+            Some(("group", ms)) => match ms.parse::<u64>() {
+                Ok(ms) if ms > 0 => Ok(Durability::GroupCommit(Duration::from_millis(ms))),
+                _ => Err(format!("Invalid group commit interval: {}", ms)),
+            },
+            _ => Err(format!(
+                "Unknown durability mode '{}'. Use one of: always | group[:<ms>] | os",
+                s
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Durability {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Durability::Always => write!(f, "always"),
+            Durability::GroupCommit(interval) => write!(f, "group:{}", interval.as_millis()),
+            Durability::Os => write!(f, "os"),
+        }
+    }
+}
+
+struct Progress {
+    written: u64,
+    synced: u64,
+    // After a failed fsync the kernel may have dropped the dirty pages, so a
+    // later fsync succeeding proves nothing; every write fails from then on
+    failed: Option<(std::io::ErrorKind, String)>,
+}
+
+impl Progress {
+    fn check(&self) -> std::io::Result<()> {
+        match &self.failed {
+            Some((kind, message)) => Err(std::io::Error::new(
+                *kind,
+                format!("An earlier fsync failed, writes are refused: {}", message),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct Syncer {
+    pub mode: Durability,
+    data_file: Arc<Mutex<File>>,
+    desc_file: Arc<Mutex<File>>,
+    progress: Mutex<Progress>,
+    synced: Condvar,
+}
+
+impl Syncer {
+    pub fn new(
+        mode: Durability,
+        data_file: Arc<Mutex<File>>,
+        desc_file: Arc<Mutex<File>>,
+    ) -> Arc<Syncer> {
+        let syncer = Arc::new(Syncer {
+            mode,
+            data_file,
+            desc_file,
+            progress: Mutex::new(Progress {
+                written: 0,
+                synced: 0,
+                failed: None,
+            }),
+            synced: Condvar::new(),
+        });
+        if let Durability::GroupCommit(interval) = mode {
+            spawn_group_commit(&syncer, interval);
+        }
+        syncer
+    }
+
+    /// Has to be called under the core write lock, right after the records
+    /// of one operation were appended. Returns a ticket for `wait`.
+    pub fn written(&self) -> std::io::Result<u64> {
+        self.progress.lock().unwrap().check()?;
+        match self.mode {
+            Durability::Always => {
+                self.sync_files()?;
+                Ok(0)
+            }
+            Durability::GroupCommit(_) => {
+                let mut progress = self.progress.lock().unwrap();
+                progress.written += 1;
+                Ok(progress.written)
+            }
+            Durability::Os => Ok(0),
+        }
+    }
+
+    /// Blocks until the write behind `ticket` is on disk, fails if the fsync
+    /// covering it did. Must not be called while holding the core write lock.
+    pub fn wait(&self, ticket: u64) -> std::io::Result<()> {
+        if let Durability::GroupCommit(_) = self.mode {
+            let mut progress = self.progress.lock().unwrap();
+            while progress.synced < ticket {
+                progress.check()?;
+                progress = self.synced.wait(progress).unwrap();
+            }
+        }
+        Ok(())
+    }
+
+    pub fn sync_files(&self) -> std::io::Result<()> {
+        let synced = self
+            .data_file
+            .lock()
+            .unwrap()
+            .sync_data()
+            .and_then(|_| self.desc_file.lock().unwrap().sync_data());
+        if let Err(e) = &synced {
+            let mut progress = self.progress.lock().unwrap();
+            progress.failed = Some((e.kind(), e.to_string()));
+            self.synced.notify_all();
+        }
+        synced
+    }
+
+    fn sync_pending(&self) -> std::io::Result<()> {
+        let target = {
+            let progress = self.progress.lock().unwrap();
+            if progress.written == progress.synced || progress.failed.is_some() {
+                return Ok(());
+            }
+            progress.written
+        };
+        self.sync_files()?;
+
+        let mut progress = self.progress.lock().unwrap();
+        progress.synced = target;
+        self.synced.notify_all();
+        Ok(())
+    }
+}
+
+fn spawn_group_commit(syncer: &Arc<Syncer>, interval: Duration) {
+    let syncer = Arc::downgrade(syncer);
+    thread::spawn(move || {
+        loop {
+            thread::sleep(interval);
+            let Some(syncer) = syncer.upgrade() else {
+                break;
+            };
+            if let Err(e) = syncer.sync_pending() {
+                eprintln!("Group commit fsync failed: {}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+
+    use super::*;
+
+    fn syncer(mode: Durability, path: &str) -> Arc<Syncer> {
+        let open = || {
+            Arc::new(Mutex::new(
+                OpenOptions::new().write(true).open(path).unwrap(),
+            ))
+        };
+        Syncer::new(mode, open(), open())
+    }
+
+    fn temp_file(dir: &tempfile::TempDir) -> String {
+        let path = dir.path().join("file").to_str().unwrap().to_string();
+        File::create(&path).unwrap();
+        path
+    }
+
+    // fsync of a character device fails with EINVAL
+    const UNSYNCABLE: &str = "/dev/null";
+
+    #[test]
+    fn always_syncs_before_returning() {
+        let dir = tempfile::tempdir().unwrap();
+        let syncer = syncer(Durability::Always, &temp_file(&dir));
+        let ticket = syncer.written().unwrap();
+        syncer.wait(ticket).unwrap();
+    }
+
+    #[test]
+    fn group_commit_wakes_waiters() {
+        let dir = tempfile::tempdir().unwrap();
+        let syncer = syncer(
+            Durability::GroupCommit(Duration::from_millis(1)),
+            &temp_file(&dir),
+        );
+        assert_eq!(syncer.written().unwrap(), 1);
+        assert_eq!(syncer.written().unwrap(), 2);
+        syncer.wait(2).unwrap();
+        assert_eq!(syncer.progress.lock().unwrap().synced, 2);
+    }
+
+    #[test]
+    fn os_never_waits() {
+        let syncer = syncer(Durability::Os, UNSYNCABLE);
+        let ticket = syncer.written().unwrap();
+        syncer.wait(ticket).unwrap();
+    }
+
+    #[test]
+    fn failed_sync_fails_the_write() {
+        let syncer = syncer(Durability::Always, UNSYNCABLE);
+        assert!(syncer.written().is_err());
+        assert!(syncer.written().is_err());
+    }
+
+    #[test]
+    fn failed_group_commit_wakes_waiters_with_an_error() {
+        let syncer = syncer(
+            Durability::GroupCommit(Duration::from_millis(1)),
+            UNSYNCABLE,
+        );
+        let ticket = syncer.written().unwrap();
+        assert!(syncer.wait(ticket).is_err());
+        // Later writes are refused instead of trusting a later fsync
+        assert!(syncer.written().is_err());
+    }
+}
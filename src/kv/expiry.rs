@@ -0,0 +1,82 @@
+use std::{
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::kv::core::Core;
+
+const REAPER_INTERVAL: Duration = Duration::from_secs(1);
+/// Longest TTL taken from clients, far from overflowing the timestamps.
+pub const MAX_TTL: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Absolute expiry timestamp for a TTL, 0 meaning the key never expires.
+/// Runs under the write lock, so it saturates rather than panic.
+pub fn expires_at(ttl: Option<Duration>) -> u64 {
+    match ttl {
+        Some(ttl) => {
+            let at = (now_millis() as u128).saturating_add(ttl.as_millis());
+            u64::try_from(at).unwrap_or(u64::MAX)
+        }
+        None => 0,
+    }
+}
+
+/// Refuses a TTL from a client that is 0 or longer than `MAX_TTL`.
+pub fn check_ttl(ttl: Duration) -> Result<Duration, &'static str> {
+    if ttl.is_zero() || ttl > MAX_TTL {
+        return Err("TTL must be above 0 and at most 100 years");
+    }
+    Ok(ttl)
+}
+
+/// Expired keys are already invisible to readers; the reaper soft-deletes
+/// them so they stop taking memory and get dropped by compaction.
+pub fn spawn_reaper(core: &Arc<Core>) {
+    let core = Arc::downgrade(core);
+    thread::spawn(move || {
+        loop {
+            thread::sleep(REAPER_INTERVAL);
+            let Some(core) = core.upgrade() else {
+                break;
+            };
+            for key in core.objects.expired_keys(now_millis()) {
+                if let Err(e) = core.delete_expired(&key) {
+                    eprintln!("Failed to reap expired key '{}': {}", key, e);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expires_at_saturates() {
+        assert_eq!(expires_at(None), 0);
+        assert_eq!(expires_at(Some(Duration::MAX)), u64::MAX);
+        let at = expires_at(Some(Duration::from_secs(10)));
+        assert!(at > now_millis() && at <= now_millis() + 10_000);
+    }
+
+    #[test]
+    fn check_ttl_bounds() {
+        assert!(check_ttl(Duration::ZERO).is_err());
+        assert!(check_ttl(MAX_TTL + Duration::from_millis(1)).is_err());
+        assert!(check_ttl(Duration::from_secs(u64::MAX)).is_err());
+        assert_eq!(check_ttl(MAX_TTL), Ok(MAX_TTL));
+        assert_eq!(
+            check_ttl(Duration::from_millis(1)),
+            Ok(Duration::from_millis(1))
+        );
+    }
+}
@@ -1,30 +1,35 @@
-use crate::DIR_PATH;
-
-use std::error::Error;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
 use std::sync::{Arc, Mutex};
 
-const HEADER_SIZE: i64 = 8;
+const MAGIC: u32 = 0xDEADBEEF;
+// magic (4) + payload length (4) + payload crc32 (4)
+pub const HEADER_SIZE: u64 = 12;
+pub const CHECKSUM_SIZE: usize = 4;
+
+// Both files start with a magic naming the file and the format version,
+// so files written in another layout are refused instead of being read
+// as one long torn tail.
+const DATA_FILE_MAGIC: [u8; 4] = *b"HBKD";
+const DESC_FILE_MAGIC: [u8; 4] = *b"HBKI";
+pub const FORMAT_VERSION: u32 = 1;
+// magic (4) + format version (4)
+pub const FILE_HEADER_SIZE: u64 = 8;
 
 pub fn update_chunk_in_file(
     offset: u64,
     data: Vec<u8>,
     file: Arc<Mutex<File>>,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
+) -> std::io::Result<()> {
     let mut file = file.lock().unwrap();
     file.seek(SeekFrom::Start(offset))?;
-    let meta = file.metadata()?;
-    println!("meta len {}", meta.len());
     file.write_all(&data)?;
     file.flush()?;
     Ok(())
 }
 
-pub fn save_desc_in_file(
-    mut data: Vec<u8>,
-    file: Arc<Mutex<File>>,
-) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+pub fn save_desc_in_file(mut data: Vec<u8>, file: Arc<Mutex<File>>) -> std::io::Result<u64> {
     let mut file = file.lock().unwrap();
     let offset: u64 = file.seek(SeekFrom::End(0))? as u64;
 
@@ -32,27 +37,118 @@ pub fn save_desc_in_file(
 
     let start = data.len() - 8;
     data[start..].copy_from_slice(&offset_bytes);
+    append_checksum(&mut data);
 
-    file.write_all(&data).expect("write data error");
+    file.write_all(&data)?;
 
     Ok(offset)
 }
 
-pub fn save_object_in_file(
-    data: &Vec<u8>,
+pub fn update_desc_in_file(
+    offset: u64,
+    mut data: Vec<u8>,
     file: Arc<Mutex<File>>,
-) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+) -> std::io::Result<()> {
+    append_checksum(&mut data);
+    update_chunk_in_file(offset, data, file)
+}
+
+/// Returns the serialized descriptor if the trailing checksum matches.
+pub fn verify_desc(record: &[u8]) -> Option<&[u8]> {
+    let (desc, checksum) = record.split_at(record.len().checked_sub(CHECKSUM_SIZE)?);
+    let expected = u32::from_le_bytes(checksum.try_into().ok()?);
+    if crc32fast::hash(desc) == expected {
+        Some(desc)
+    } else {
+        None
+    }
+}
+
+fn append_checksum(data: &mut Vec<u8>) {
+    let checksum = crc32fast::hash(data);
+    data.extend_from_slice(&checksum.to_le_bytes());
+}
+
+pub fn save_object_in_file(data: &Vec<u8>, file: Arc<Mutex<File>>) -> std::io::Result<u64> {
     let mut file = file.lock().unwrap();
 
     let offset: u64 = file.seek(SeekFrom::End(0))? as u64;
 
-    let header = create_header(data.len() as u64);
+    let header = create_header(data);
 
-    file.write_all(&header).expect("write header error");
-    file.write_all(&data).expect("write data error");
+    file.write_all(&header)?;
+    file.write_all(data)?;
 
     Ok(offset)
 }
+
+/// Appends a record whose payload is the `size` bytes read from `source`,
+/// copied in chunks so it never has to fit in memory. `checksum` is the
+/// crc32 of those bytes, see `checksum_reader`.
+pub fn save_object_from_reader(
+    source: &mut impl Read,
+    size: u64,
+    checksum: u32,
+    file: Arc<Mutex<File>>,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let length = u32::try_from(size).map_err(|_| "Value is too large for a data record")?;
+    let mut file = file.lock().unwrap();
+
+    let offset: u64 = file.seek(SeekFrom::End(0))?;
+    let written = file
+        .write_all(&header_bytes(length, checksum))
+        .and_then(|_| std::io::copy(&mut source.take(size), &mut *file));
+    if !matches!(written, Ok(copied) if copied == size) {
+        // Don't leave a torn record in front of the next append. Nothing can
+        // have mapped these bytes, see `mmap::map_file`.
+        file.set_len(offset)?;
+        written?;
+        return Err(format!("Source ended before {} bytes were copied", size).into());
+    }
+
+    Ok(offset)
+}
+
+/// Size and crc32 of everything left in `source`.
+pub fn checksum_reader(source: &mut impl Read) -> std::io::Result<(u64, u32)> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let read = source.read(&mut buffer)?;
+        if read == 0 {
+            return Ok((size, hasher.finalize()));
+        }
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+}
+
+/// Validates the header of the record at `offset` without reading the
+/// payload and returns the payload checksum.
+pub fn read_header(
+    file: &File,
+    offset: u64,
+    size: u64,
+) -> Result<u32, Box<dyn std::error::Error + Send + Sync>> {
+    let mut header = [0u8; HEADER_SIZE as usize];
+    file.read_exact_at(&mut header, offset)?;
+
+    let magic = u32::from_be_bytes(header[0..4].try_into()?);
+    if magic != MAGIC {
+        return Err(format!("Bad magic {:#010x} at offset {}", magic, offset).into());
+    }
+    let length = u32::from_be_bytes(header[4..8].try_into()?) as u64;
+    if length != size {
+        return Err(format!(
+            "Length mismatch at offset {}: header says {}, descriptor says {}",
+            offset, length, size
+        )
+        .into());
+    }
+    Ok(u32::from_be_bytes(header[8..12].try_into()?))
+}
+
 pub fn read_object_from_file(
     file: Arc<Mutex<File>>,
     offset: u64,
@@ -62,28 +158,288 @@ pub fn read_object_from_file(
 
     file.seek(SeekFrom::Start(offset as u64))?;
 
-    file.seek(SeekFrom::Current(HEADER_SIZE as i64))?;
+    let mut record = vec![0u8; (HEADER_SIZE + size) as usize];
+    file.read_exact(&mut record)?;
+    decode_object(&record, offset, size)?;
 
-    let mut buffer = vec![0u8; size as usize];
-    file.read_exact(&mut buffer)?;
+    Ok(record.split_off(HEADER_SIZE as usize))
+}
 
-    Ok(buffer)
+/// Validates the data record at the start of `record` against its header
+/// and returns the payload.
+pub fn decode_object(
+    record: &[u8],
+    offset: u64,
+    size: u64,
+) -> Result<&[u8], Box<dyn std::error::Error + Send + Sync>> {
+    let end = (HEADER_SIZE + size) as usize;
+    if record.len() < end {
+        return Err(format!("Record at offset {} is cut short", offset).into());
+    }
+
+    let magic = u32::from_be_bytes(record[0..4].try_into()?);
+    if magic != MAGIC {
+        return Err(format!("Bad magic {:#010x} at offset {}", magic, offset).into());
+    }
+    let length = u32::from_be_bytes(record[4..8].try_into()?) as u64;
+    if length != size {
+        return Err(format!(
+            "Length mismatch at offset {}: header says {}, descriptor says {}",
+            offset, length, size
+        )
+        .into());
+    }
+
+    let payload = &record[HEADER_SIZE as usize..end];
+    let checksum = u32::from_be_bytes(record[8..12].try_into()?);
+    if crc32fast::hash(payload) != checksum {
+        return Err(format!("Checksum mismatch at offset {}", offset).into());
+    }
+
+    Ok(payload)
 }
 
-pub fn get_desc_filename(prefix: &str) -> String {
-    format!("{}/{}.Desc.bindb", DIR_PATH, prefix)
+pub fn open_data_file(path: &str) -> std::io::Result<File> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)?;
+    check_file_header(&mut file, path, DATA_FILE_MAGIC)?;
+    Ok(file)
 }
-pub fn get_data_filename(prefix: &str) -> String {
-    format!("{}/{}.Data.bindb", DIR_PATH, prefix)
+
+pub fn open_desc_file(path: &str) -> std::io::Result<File> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .truncate(false)
+        .create(true)
+        .open(path)?;
+    check_file_header(&mut file, path, DESC_FILE_MAGIC)?;
+    Ok(file)
 }
 
-fn create_header(length: u64) -> [u8; 8] {
-    let mut header = [0u8; 8];
+/// Replaces `path` with an empty data file, used for compaction output.
+pub fn create_data_file(path: &str) -> std::io::Result<File> {
+    let mut file = File::create(path)?;
+    file.write_all(&file_header(DATA_FILE_MAGIC))?;
+    Ok(file)
+}
+
+/// Replaces `path` with an empty descriptor file.
+pub fn create_desc_file(path: &str) -> std::io::Result<File> {
+    let mut file = File::create(path)?;
+    file.write_all(&file_header(DESC_FILE_MAGIC))?;
+    Ok(file)
+}
+
+/// Whether `bytes` start like a data or descriptor file of any version.
+pub fn has_file_header(bytes: &[u8]) -> bool {
+    bytes.starts_with(&DATA_FILE_MAGIC) || bytes.starts_with(&DESC_FILE_MAGIC)
+}
+
+fn file_header(magic: [u8; 4]) -> [u8; FILE_HEADER_SIZE as usize] {
+    let mut header = [0u8; FILE_HEADER_SIZE as usize];
+    header[0..4].copy_from_slice(&magic);
+    header[4..8].copy_from_slice(&FORMAT_VERSION.to_be_bytes());
+    header
+}
+
+// Writes the header into a new file, otherwise refuses to go on unless the
+// file was written in this format. Leaves the file positioned after it.
+fn check_file_header(file: &mut File, path: &str, magic: [u8; 4]) -> std::io::Result<()> {
+    let expected = file_header(magic);
+    let len = file.metadata()?.len();
+    let mut header = vec![0u8; len.min(FILE_HEADER_SIZE) as usize];
+    file.read_exact_at(&mut header, 0)?;
+
+    // Empty, or a crash cut the header of a new file short
+    if len < FILE_HEADER_SIZE && expected.starts_with(&header) {
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&expected)?;
+        file.sync_all()?;
+        return Ok(());
+    }
+    if header[..] == expected[..] {
+        file.seek(SeekFrom::Start(FILE_HEADER_SIZE))?;
+        return Ok(());
+    }
+    let message = if len >= FILE_HEADER_SIZE && header[0..4] == magic {
+        let version = u32::from_be_bytes(header[4..8].try_into().unwrap());
+        format!(
+            "{} uses format version {}, this build only reads version {}",
+            path, version, FORMAT_VERSION
+        )
+    } else {
+        format!(
+            "{} has no format header and is not in the layout of older releases, \
+             which is upgraded on open. Move the file out of the data directory \
+             to start empty",
+            path
+        )
+    };
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        message,
+    ))
+}
+
+pub fn get_desc_filename(dir: &str, prefix: &str) -> String {
+    format!("{}/{}.Desc.bindb", dir, prefix)
+}
+pub fn get_data_filename(dir: &str, prefix: &str) -> String {
+    format!("{}/{}.Data.bindb", dir, prefix)
+}
+pub fn get_upload_filename(dir: &str, prefix: &str, id: &str) -> String {
+    format!("{}/{}.{}.upload", dir, prefix, id)
+}
+
+/// Removes uploads a crash left staged.
+pub fn remove_stale_uploads(dir: &str, prefix: &str) -> std::io::Result<()> {
+    let start = format!("{}.", prefix);
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let stale = path
+            .file_name()
+            .and_then(|name| name.to_str()?.strip_prefix(&start)?.strip_suffix(".upload"))
+            .is_some_and(|id| !id.contains('.'));
+        if stale {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+fn create_header(data: &[u8]) -> [u8; HEADER_SIZE as usize] {
+    header_bytes(data.len() as u32, crc32fast::hash(data))
+}
 
-    header[0..4].copy_from_slice(&0xDEADBEEF_u32.to_be_bytes());
+fn header_bytes(length: u32, checksum: u32) -> [u8; HEADER_SIZE as usize] {
+    let mut header = [0u8; HEADER_SIZE as usize];
 
-    let length_32 = length as u32;
-    header[4..8].copy_from_slice(&length_32.to_be_bytes());
+    header[0..4].copy_from_slice(&MAGIC.to_be_bytes());
+    header[4..8].copy_from_slice(&length.to_be_bytes());
+    header[8..12].copy_from_slice(&checksum.to_be_bytes());
 
     header
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_data_path(dir: &tempfile::TempDir) -> String {
+        get_data_filename(dir.path().to_str().unwrap(), "test")
+    }
+
+    #[test]
+    fn verify_desc_rejects_flipped_byte() {
+        let mut record = b"descriptor".to_vec();
+        append_checksum(&mut record);
+        assert_eq!(verify_desc(&record), Some(&b"descriptor"[..]));
+
+        record[3] ^= 1;
+        assert_eq!(verify_desc(&record), None);
+        assert_eq!(verify_desc(&[0u8; 2]), None);
+    }
+
+    #[test]
+    fn read_object_detects_corrupted_payload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = temp_data_path(&dir);
+        let file = Arc::new(Mutex::new(open_data_file(&path).unwrap()));
+        let offset = save_object_in_file(&b"hello".to_vec(), Arc::clone(&file)).unwrap();
+        assert_eq!(
+            read_object_from_file(Arc::clone(&file), offset, 5).unwrap(),
+            b"hello"
+        );
+
+        // The data file is opened for appending, which ignores the offset
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .write_all_at(b"j", offset + HEADER_SIZE)
+            .unwrap();
+        let error = read_object_from_file(Arc::clone(&file), offset, 5).unwrap_err();
+        assert!(error.to_string().contains("Checksum mismatch"));
+    }
+
+    #[test]
+    fn decode_object_checks_magic_and_length() {
+        let mut record = create_header(b"abc").to_vec();
+        record.extend_from_slice(b"abc");
+        assert_eq!(decode_object(&record, 0, 3).unwrap(), b"abc");
+        assert!(decode_object(&record, 0, 2).is_err());
+        assert!(decode_object(&record[..10], 0, 3).is_err());
+
+        record[0] = 0;
+        assert!(decode_object(&record, 0, 3).is_err());
+    }
+
+    #[test]
+    fn new_files_get_a_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = temp_data_path(&dir);
+        drop(open_data_file(&path).unwrap());
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            file_header(DATA_FILE_MAGIC).to_vec()
+        );
+
+        // Reopening keeps the header and appends after it
+        let file = Arc::new(Mutex::new(open_data_file(&path).unwrap()));
+        let offset = save_object_in_file(&b"a".to_vec(), file).unwrap();
+        assert_eq!(offset, FILE_HEADER_SIZE);
+    }
+
+    #[test]
+    fn files_without_header_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = temp_data_path(&dir);
+        // A record in the old layout, straight at the start of the file
+        std::fs::write(&path, create_header(b"old")).unwrap();
+
+        let error = open_data_file(&path).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::read(&path).unwrap().len(), HEADER_SIZE as usize);
+    }
+
+    #[test]
+    fn other_format_versions_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = get_desc_filename(dir.path().to_str().unwrap(), "test");
+        let mut header = file_header(DESC_FILE_MAGIC);
+        header[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_be_bytes());
+        std::fs::write(&path, header).unwrap();
+
+        let error = open_desc_file(&path).unwrap_err();
+        assert!(error.to_string().contains("format version"));
+        // A data file is not taken for a descriptor file either
+        std::fs::write(&path, file_header(DATA_FILE_MAGIC)).unwrap();
+        assert!(open_desc_file(&path).is_err());
+    }
+
+    #[test]
+    fn failed_copy_leaves_no_torn_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = temp_data_path(&dir);
+        let file = Arc::new(Mutex::new(open_data_file(&path).unwrap()));
+        save_object_in_file(&b"kept".to_vec(), Arc::clone(&file)).unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+
+        // The source ends before the announced size
+        let mut source: &[u8] = b"short";
+        assert!(save_object_from_reader(&mut source, 100, 0, Arc::clone(&file)).is_err());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+
+        let mut source: &[u8] = b"whole";
+        let (size, checksum) = checksum_reader(&mut &b"whole"[..]).unwrap();
+        let offset =
+            save_object_from_reader(&mut source, size, checksum, Arc::clone(&file)).unwrap();
+        assert_eq!(offset, len);
+        assert_eq!(read_object_from_file(file, offset, size).unwrap(), b"whole");
+    }
+}
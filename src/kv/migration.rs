@@ -0,0 +1,297 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom},
+    os::unix::fs::FileExt,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::kv::{
+    io_service as io,
+    objects::{Key256, Kind, ObjectDescriptor},
+};
+
+// Releases before the format header wrote 293-byte descriptors without a
+// checksum, and data records with an 8-byte header: magic + length.
+const LEGACY_RECORD_SIZE: usize = 293;
+const LEGACY_HEADER_SIZE: u64 = 8;
+const LEGACY_MAGIC: u32 = 0xDEADBEEF;
+
+#[derive(Serialize, Deserialize)]
+struct LegacyDescriptor {
+    key: Key256,
+    kind: Kind,
+    offset: u64,
+    size: u64,
+    is_deleted: bool,
+    _desc_offset: u64,
+}
+
+fn legacy_filename(path: &str) -> String {
+    format!("{}.legacy", path)
+}
+
+fn migrate_filename(path: &str) -> String {
+    format!("{}.migrate", path)
+}
+
+fn sync_dir(dir: &str) -> std::io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Rewrites a database stored in the layout of releases before the format
+/// header. The old files are kept next to the new ones as `*.legacy`, and
+/// an interrupted upgrade starts over from them on the next open.
+pub fn upgrade_legacy(dir: &str, prefix: &str) -> std::io::Result<()> {
+    let data_path = io::get_data_filename(dir, prefix);
+    let desc_path = io::get_desc_filename(dir, prefix);
+    let data_legacy = legacy_filename(&data_path);
+    let desc_legacy = legacy_filename(&desc_path);
+
+    // The descriptor file is moved last, so its old name tells whether the
+    // files still have to be set aside
+    if is_legacy_desc_file(&desc_path)? {
+        if Path::new(&data_path).exists() {
+            fs::rename(&data_path, &data_legacy)?;
+        }
+        fs::rename(&desc_path, &desc_legacy)?;
+        sync_dir(dir)?;
+    }
+    if Path::new(&desc_path).exists() || !Path::new(&desc_legacy).exists() {
+        return Ok(());
+    }
+
+    let data_migrate = migrate_filename(&data_path);
+    let desc_migrate = migrate_filename(&desc_path);
+    let objects = rewrite(&data_legacy, &desc_legacy, &data_migrate, &desc_migrate)?;
+    fs::rename(&data_migrate, &data_path)?;
+    fs::rename(&desc_migrate, &desc_path)?;
+    sync_dir(dir)?;
+    println!(
+        "Upgraded '{}' to format version {} ({} objects), the old files are kept as {} and {}",
+        prefix,
+        io::FORMAT_VERSION,
+        objects,
+        data_legacy,
+        desc_legacy
+    );
+    Ok(())
+}
+
+// Only files that are whole legacy records and can't be the current format
+fn is_legacy_desc_file(path: &str) -> std::io::Result<bool> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let len = file.metadata()?.len();
+    if len == 0 || len % LEGACY_RECORD_SIZE as u64 != 0 {
+        return Ok(false);
+    }
+    let mut record = vec![0u8; LEGACY_RECORD_SIZE];
+    file.read_exact(&mut record)?;
+    if io::has_file_header(&record) {
+        return Ok(false);
+    }
+    Ok(bincode::deserialize::<LegacyDescriptor>(&record).is_ok())
+}
+
+// Copies the live objects into new files, in the order they were written.
+// Versions are handed out in that order too.
+fn rewrite(
+    data_legacy: &str,
+    desc_legacy: &str,
+    data_path: &str,
+    desc_path: &str,
+) -> std::io::Result<usize> {
+    let mut desc_file = File::open(desc_legacy)?;
+    let data_file = match File::open(data_legacy) {
+        Ok(file) => Some(file),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+
+    // Deletes were written over the record in place, so the last record of
+    // a key says whether it is alive
+    let mut latest: HashMap<String, (usize, LegacyDescriptor)> = HashMap::new();
+    let mut buffer = vec![0u8; LEGACY_RECORD_SIZE];
+    desc_file.seek(SeekFrom::Start(0))?;
+    let mut position = 0;
+    while desc_file.read_exact(&mut buffer).is_ok() {
+        match bincode::deserialize::<LegacyDescriptor>(&buffer) {
+            Ok(desc) => {
+                latest.insert(desc.key.to_string(), (position, desc));
+            }
+            Err(e) => eprintln!("Upgrade: skipped unreadable descriptor: {}", e),
+        }
+        position += 1;
+    }
+    let mut live: Vec<(usize, LegacyDescriptor)> = latest
+        .into_values()
+        .filter(|(_, desc)| !desc.is_deleted)
+        .collect();
+    live.sort_by_key(|(position, _)| *position);
+
+    let new_data_file = Arc::new(Mutex::new(io::create_data_file(data_path)?));
+    let new_desc_file = Arc::new(Mutex::new(io::create_desc_file(desc_path)?));
+    let mut objects = 0;
+    for (version, (_, legacy)) in live.into_iter().enumerate() {
+        let data = match &data_file {
+            Some(file) => read_legacy_object(file, legacy.offset, legacy.size),
+            None => Err(std::io::Error::from(std::io::ErrorKind::NotFound)),
+        };
+        let data = match data {
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!(
+                    "Upgrade: skipped '{}', its value can't be read: {}",
+                    legacy.key.to_string(),
+                    e
+                );
+                continue;
+            }
+        };
+        let offset = io::save_object_in_file(&data, Arc::clone(&new_data_file))?;
+        let desc = ObjectDescriptor {
+            key: legacy.key,
+            kind: legacy.kind,
+            offset,
+            size: legacy.size,
+            is_deleted: false,
+            expires_at: 0,
+            version: version as u64 + 1,
+            txn_id: 0,
+            is_commit: false,
+            desc_offset: 0,
+        };
+        let record = bincode::serialize(&desc).map_err(std::io::Error::other)?;
+        io::save_desc_in_file(record, Arc::clone(&new_desc_file))?;
+        objects += 1;
+    }
+    new_data_file.lock().unwrap().sync_all()?;
+    new_desc_file.lock().unwrap().sync_all()?;
+    Ok(objects)
+}
+
+fn read_legacy_object(file: &File, offset: u64, size: u64) -> std::io::Result<Vec<u8>> {
+    let mut header = [0u8; LEGACY_HEADER_SIZE as usize];
+    file.read_exact_at(&mut header, offset)?;
+    let magic = u32::from_be_bytes(header[0..4].try_into().unwrap());
+    let length = u32::from_be_bytes(header[4..8].try_into().unwrap());
+    if magic != LEGACY_MAGIC || length as u64 != size {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Invalid record header at offset {}", offset),
+        ));
+    }
+    let mut data = vec![0u8; size as usize];
+    file.read_exact_at(&mut data, offset + LEGACY_HEADER_SIZE)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::kv::{
+        core::{Core, Options},
+        durability::Durability,
+    };
+
+    // Writes records the way releases before the format header did
+    fn write_legacy(dir: &str, records: &[(&str, &[u8], bool)]) {
+        let mut data = File::create(io::get_data_filename(dir, "test")).unwrap();
+        let mut desc = File::create(io::get_desc_filename(dir, "test")).unwrap();
+        let mut offset = 0;
+        for (i, (key, value, is_deleted)) in records.iter().enumerate() {
+            data.write_all(&LEGACY_MAGIC.to_be_bytes()).unwrap();
+            data.write_all(&(value.len() as u32).to_be_bytes()).unwrap();
+            data.write_all(value).unwrap();
+            let record = LegacyDescriptor {
+                key: Key256::new(key),
+                kind: Kind::String,
+                offset,
+                size: value.len() as u64,
+                is_deleted: *is_deleted,
+                _desc_offset: (i * LEGACY_RECORD_SIZE) as u64,
+            };
+            let record = bincode::serialize(&record).unwrap();
+            assert_eq!(record.len(), LEGACY_RECORD_SIZE);
+            desc.write_all(&record).unwrap();
+            offset += LEGACY_HEADER_SIZE + value.len() as u64;
+        }
+    }
+
+    fn open(dir: &str) -> Arc<Core> {
+        let options = Options {
+            data_dir: dir.to_string(),
+            durability: Durability::Os,
+            cache_bytes: None,
+        };
+        Core::new("test", options).unwrap()
+    }
+
+    fn value(core: &Core, key: &str) -> Option<Vec<u8>> {
+        core.get(key).map(|object| object.data)
+    }
+
+    #[test]
+    fn legacy_files_are_upgraded() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().to_str().unwrap();
+        write_legacy(
+            dir,
+            &[
+                ("kept", b"one", false),
+                ("overwritten", b"old", false),
+                ("deleted", b"gone", true),
+                ("overwritten", b"new", false),
+            ],
+        );
+
+        let core = open(dir);
+        assert_eq!(value(&core, "kept").as_deref(), Some(&b"one"[..]));
+        assert_eq!(value(&core, "overwritten").as_deref(), Some(&b"new"[..]));
+        assert_eq!(value(&core, "deleted"), None);
+        drop(core);
+
+        let data_legacy = legacy_filename(&io::get_data_filename(dir, "test"));
+        assert!(Path::new(&data_legacy).exists());
+        let core = open(dir);
+        assert_eq!(value(&core, "overwritten").as_deref(), Some(&b"new"[..]));
+    }
+
+    #[test]
+    fn interrupted_upgrade_starts_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().to_str().unwrap();
+        write_legacy(dir, &[("key", b"value", false)]);
+        // As if a crash came right after the old files were set aside
+        for path in [
+            io::get_data_filename(dir, "test"),
+            io::get_desc_filename(dir, "test"),
+        ] {
+            fs::rename(&path, legacy_filename(&path)).unwrap();
+        }
+        File::create(migrate_filename(&io::get_data_filename(dir, "test"))).unwrap();
+
+        let core = open(dir);
+        assert_eq!(value(&core, "key").as_deref(), Some(&b"value"[..]));
+    }
+
+    #[test]
+    fn current_files_are_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().to_str().unwrap();
+        drop(open(dir));
+
+        upgrade_legacy(dir, "test").unwrap();
+        let desc_legacy = legacy_filename(&io::get_desc_filename(dir, "test"));
+        assert!(!Path::new(&desc_legacy).exists());
+    }
+}
@@ -0,0 +1,63 @@
+use std::{
+    error::Error,
+    fs::File,
+    sync::{Mutex, RwLock},
+};
+
+use memmap2::Mmap;
+
+use crate::kv::io_service as io;
+
+/// Read-only mapping of the data file. Concurrent GETs slice payloads out
+/// of it under a shared lock; the mapping is only replaced when a read runs
+/// past its end because the file grew.
+pub struct MappedFile {
+    map: RwLock<Mmap>,
+}
+
+impl MappedFile {
+    pub fn new(file: &File) -> std::io::Result<MappedFile> {
+        Ok(MappedFile {
+            map: RwLock::new(map_file(file)?),
+        })
+    }
+
+    pub fn read(
+        &self,
+        file: &Mutex<File>,
+        offset: u64,
+        size: u64,
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let end = offset + io::HEADER_SIZE + size;
+        if (self.map.read().unwrap().len() as u64) < end {
+            self.remap(&file.lock().unwrap())?;
+        }
+
+        let map = self.map.read().unwrap();
+        if (map.len() as u64) < end {
+            return Err(format!(
+                "Record at offset {} is past the end of the data file",
+                offset
+            )
+            .into());
+        }
+        Ok(io::decode_object(&map[offset as usize..], offset, size)?.to_vec())
+    }
+
+    pub fn remap(&self, file: &File) -> std::io::Result<()> {
+        let map = map_file(file)?;
+        *self.map.write().unwrap() = map;
+        Ok(())
+    }
+}
+
+fn map_file(file: &File) -> std::io::Result<Mmap> {
+    // SAFETY: mapped bytes are never modified or cut off. The data file is
+    // append-only, torn tails are cut before the first mapping, and compaction
+    // writes a new file that is renamed into place and remapped. The one
+    // shrink, `save_object_from_reader` rolling back a failed copy, only
+    // removes bytes past the length the file had when it took the file lock.
+    // Mappings are only made under that lock or, by compaction, under the
+    // write lock, and the copy holds both, so none reaches those bytes.
+    unsafe { Mmap::map(file) }
+}
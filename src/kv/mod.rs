@@ -1,3 +1,14 @@
+pub mod blob;
+pub mod cache;
+pub mod compaction;
 pub mod core;
+pub mod databases;
+pub mod durability;
+pub mod expiry;
 mod io_service;
+mod migration;
+pub mod mmap;
 pub mod objects;
+pub mod pattern;
+mod recovery;
+pub mod transaction;
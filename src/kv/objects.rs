@@ -1,12 +1,18 @@
 use core::fmt;
 use std::error::Error;
-use std::io::Read;
+use std::io::{Read, Seek, SeekFrom};
 use std::sync::{Mutex, RwLock};
-use std::{collections::HashMap, fs::File, str::FromStr, sync::Arc};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::File,
+    ops::Bound,
+    str::FromStr,
+    sync::Arc,
+};
 
 use serde::{Deserialize, Serialize};
 
-use crate::kv::io_service;
+use crate::kv::{expiry, io_service, pattern::KeyPattern};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum Kind {
@@ -51,34 +57,37 @@ impl fmt::Display for Kind {
     }
 }
 
+/// Longest key in bytes, the size of `Key256`.
+pub const MAX_KEY_LEN: usize = 256;
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Key256 {
     pub bytes: Vec<u8>,
 }
 
 impl Key256 {
+    /// Keys longer than `MAX_KEY_LEN` are cut, writers reject them before.
     pub fn new(data: &str) -> Self {
         let bytes = data.as_bytes();
-        let mut vec = Vec::with_capacity(256);
+        let mut vec = Vec::with_capacity(MAX_KEY_LEN);
 
-        if bytes.len() >= 256 {
-            vec.extend_from_slice(&bytes[..256]);
+        if bytes.len() >= MAX_KEY_LEN {
+            vec.extend_from_slice(&bytes[..MAX_KEY_LEN]);
         } else {
             vec.extend_from_slice(bytes);
-            vec.resize(256, 0);
+            vec.resize(MAX_KEY_LEN, 0);
         }
 
         Key256 { bytes: vec }
     }
 
     pub fn to_string(&self) -> String {
-        let trimmed = self
+        let len = self
             .bytes
             .iter()
-            .take_while(|&&b| b != 0)
-            .map(|&b| b as char)
-            .collect();
-        trimmed
+            .position(|&b| b == 0)
+            .unwrap_or(self.bytes.len());
+        String::from_utf8_lossy(&self.bytes[..len]).into_owned()
     }
 }
 
@@ -87,9 +96,81 @@ pub struct ObjectListElement {
     pub key: String,
     pub size: u64,
     pub kind: Kind,
-    pub offset: u64,
+    pub version: u64,
+}
+
+/// Continuation token for paged listings: the position of the last
+/// returned entry in (version, key) order. Rewriting a key moves it to the
+/// end, so a key may show up twice, but none is skipped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListCursor {
+    pub version: u64,
+    pub key: String,
+}
+
+impl fmt::Display for ListCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:x}.", self.version)?;
+        for byte in self.key.as_bytes() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ListCursor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid cursor: {}", s);
+        let (version, key) = s.split_once('.').ok_or_else(invalid)?;
+        if key.len() % 2 != 0 || !key.is_ascii() {
+            return Err(invalid());
+        }
+        let bytes = (0..key.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&key[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| invalid())?;
+        Ok(ListCursor {
+            version: u64::from_str_radix(version, 16).map_err(|_| invalid())?,
+            key: String::from_utf8(bytes).map_err(|_| invalid())?,
+        })
+    }
 }
-const RECORD_SIZE: usize = 293;
+
+pub struct ListPage {
+    pub items: Vec<ObjectListElement>,
+    /// Set when more entries follow
+    pub cursor: Option<ListCursor>,
+}
+
+/// Which entries `ObjectService::list` returns.
+#[derive(Debug, Clone, Default)]
+pub struct ListFilter<'a> {
+    pub kind: Option<&'a Kind>,
+    pub pattern: Option<&'a KeyPattern>,
+    /// Keys under one of these prefixes, every key when empty
+    pub key_prefixes: &'a [String],
+}
+
+impl ListFilter<'_> {
+    fn matches(&self, key: &str, object: &Object) -> bool {
+        self.kind.is_none_or(|kind| object.desc.kind == *kind)
+            && self.pattern.is_none_or(|pattern| pattern.matches(key))
+            && under_prefixes(key, self.key_prefixes)
+    }
+}
+
+fn under_prefixes(key: &str, prefixes: &[String]) -> bool {
+    prefixes.is_empty()
+        || prefixes
+            .iter()
+            .any(|prefix| key.starts_with(prefix.as_str()))
+}
+
+// Serialized ObjectDescriptor followed by its crc32
+pub const RECORD_SIZE: usize = 318 + io_service::CHECKSUM_SIZE;
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ObjectDescriptor {
     pub key: Key256,
@@ -97,86 +178,227 @@ pub struct ObjectDescriptor {
     pub offset: u64,
     pub size: u64,
     pub is_deleted: bool,
+    // Unix time in milliseconds, 0 when the key never expires
+    pub expires_at: u64,
+    // Sequence number of the write that produced this value, increasing
+    // across all keys
+    pub version: u64,
+    // Records written by a transaction only count once the commit marker
+    // with the same id is in the log; 0 outside of transactions
+    pub txn_id: u64,
+    pub is_commit: bool,
+    // Must stay the last field, save_desc_in_file patches it in place
     pub desc_offset: u64,
 }
+impl ObjectDescriptor {
+    pub fn commit_marker(txn_id: u64) -> ObjectDescriptor {
+        ObjectDescriptor {
+            key: Key256::new(""),
+            kind: Kind::Blob,
+            offset: 0,
+            size: 0,
+            is_deleted: false,
+            expires_at: 0,
+            version: 0,
+            txn_id,
+            is_commit: true,
+            desc_offset: 0,
+        }
+    }
+    /// Carries the highest version handed out into a compacted log, which
+    /// may no longer hold the record that had it. Replays as an empty commit.
+    pub fn version_marker(version: u64) -> ObjectDescriptor {
+        ObjectDescriptor {
+            version,
+            ..ObjectDescriptor::commit_marker(0)
+        }
+    }
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at != 0 && self.expires_at <= now
+    }
+}
 #[derive(Debug, Clone)]
 pub struct Object {
     pub desc: ObjectDescriptor,
     pub data: Vec<u8>,
 }
 
+fn replay(objects: &mut HashMap<String, Object>, desc: ObjectDescriptor) {
+    let key = desc.key.to_string();
+    if desc.is_deleted {
+        objects.remove(&key);
+    } else {
+        objects.insert(key, Object { desc, data: vec![] });
+    }
+}
+
+/// Counters the core continues from after replaying the log.
+#[derive(Debug, Default)]
+pub struct LogState {
+    pub last_txn_id: u64,
+    pub last_version: u64,
+}
+
+/// Keys visited by `ObjectService::scan`, in key order.
+#[derive(Debug, Clone)]
+pub enum ScanRange {
+    Prefix(String),
+    /// From `start` (inclusive) up to `end` (exclusive), or to the last key
+    /// when `end` is `None`.
+    Range {
+        start: String,
+        end: Option<String>,
+    },
+}
+
+// Sorted copies of the map keys: by key for scans, and by (version, key)
+// for paged listings.
+#[derive(Default)]
+struct KeyIndex {
+    keys: BTreeSet<String>,
+    versions: BTreeSet<(u64, String)>,
+}
+
+impl KeyIndex {
+    fn insert(&mut self, key: &str, version: u64, previous: Option<u64>) {
+        if let Some(previous) = previous {
+            self.versions.remove(&(previous, key.to_string()));
+        }
+        self.keys.insert(key.to_string());
+        self.versions.insert((version, key.to_string()));
+    }
+
+    fn remove(&mut self, key: &str, version: u64) {
+        self.keys.remove(key);
+        self.versions.remove(&(version, key.to_string()));
+    }
+}
+
 pub struct ObjectService {
     pub objects_map: RwLock<HashMap<String, Object>>,
+    // Always locked after `objects_map`
+    key_index: RwLock<KeyIndex>,
 }
 
 impl ObjectService {
     pub fn new() -> ObjectService {
         ObjectService {
             objects_map: RwLock::new(HashMap::new()),
+            key_index: RwLock::new(KeyIndex::default()),
         }
     }
 
-    pub fn load_objects_desc(&self, file: Arc<Mutex<File>>) {
+    pub fn load_objects_desc(&self, file: Arc<Mutex<File>>) -> LogState {
         let mut buffer = vec![0u8; RECORD_SIZE];
         let mut file = file.lock().expect("Failed to lock desc_file");
+        if let Err(e) = file.seek(SeekFrom::Start(io_service::FILE_HEADER_SIZE)) {
+            eprintln!("Unable to read descriptors: {}", e);
+            return LogState::default();
+        }
 
-        let mut objects_by_key: HashMap<String, Vec<Object>> = HashMap::new();
+        // Replay the log in write order, so the newest record of a key wins
+        // and a set after a delete brings the key back. Transaction records
+        // wait for their commit marker and are dropped without one.
+        let mut replayed: HashMap<String, Object> = HashMap::new();
+        let mut pending: HashMap<u64, Vec<ObjectDescriptor>> = HashMap::new();
+        let mut record_offset = io_service::FILE_HEADER_SIZE;
+        let mut corrupted = 0;
+        let mut state = LogState::default();
         while let Ok(_) = file.read_exact(&mut buffer) {
-            match bincode::deserialize::<ObjectDescriptor>(&buffer) {
+            let offset = record_offset;
+            record_offset += RECORD_SIZE as u64;
+            let Some(record) = io_service::verify_desc(&buffer) else {
+                eprintln!(
+                    "Corrupted descriptor at offset {}: checksum mismatch",
+                    offset
+                );
+                corrupted += 1;
+                continue;
+            };
+            match bincode::deserialize::<ObjectDescriptor>(record) {
                 Ok(object_descriptor) => {
-                    let key_copy = object_descriptor.key.to_string();
-                    let object = Object {
-                        desc: object_descriptor.clone(),
-                        data: vec![],
-                    };
-
-                    objects_by_key.entry(key_copy).or_default().push(object);
+                    state.last_txn_id = state.last_txn_id.max(object_descriptor.txn_id);
+                    state.last_version = state.last_version.max(object_descriptor.version);
+                    if object_descriptor.is_commit {
+                        for desc in pending
+                            .remove(&object_descriptor.txn_id)
+                            .unwrap_or_default()
+                        {
+                            replay(&mut replayed, desc);
+                        }
+                    } else if object_descriptor.txn_id != 0 {
+                        pending
+                            .entry(object_descriptor.txn_id)
+                            .or_default()
+                            .push(object_descriptor);
+                    } else {
+                        replay(&mut replayed, object_descriptor);
+                    }
                 }
                 Err(e) => {
-                    println!("Failed to deserialize record: {:?}", e);
+                    eprintln!("Corrupted descriptor at offset {}: {:?}", offset, e);
+                    corrupted += 1;
                     continue;
                 }
             }
         }
-
-        let filtered_objects: Vec<(String, Object)> = objects_by_key
-            .into_iter()
-            .filter_map(|(key, objects)| {
-                if objects.iter().any(|obj| obj.desc.is_deleted) {
-                    None
-                } else {
-                    Some((key, objects[0].clone()))
-                }
-            })
-            .collect();
-        let hash_map_len = filtered_objects.len();
+        if !pending.is_empty() {
+            eprintln!(
+                "Rolled back {} uncommitted transaction(s) ({} record(s))",
+                pending.len(),
+                pending.values().map(Vec::len).sum::<usize>()
+            );
+        }
+        let hash_map_len = replayed.len();
 
         match self.objects_map.write() {
             Ok(mut map) => {
-                for (key, object) in filtered_objects {
-                    map.insert(key, object);
+                map.extend(replayed);
+                let mut index = self.key_index.write().unwrap();
+                for (key, object) in map.iter() {
+                    index.insert(key, object.desc.version, None);
                 }
             }
             Err(e) => {
                 eprintln!("Loading object error: {}", e)
             }
         }
+        if corrupted > 0 {
+            eprintln!("Skipped {} corrupted descriptor(s)", corrupted);
+        }
         println!(
             "Loaded object descriptors\nObjects in memory: {}",
             hash_map_len
         );
+        state
     }
 
     pub fn load_objects_data(&mut self, file: Arc<Mutex<File>>) {
         match self.objects_map.get_mut() {
             Ok(map) => {
-                for object in map {
-                    let data = io_service::read_object_from_file(
+                map.retain(|key, object| {
+                    // Blobs are read from the data file when asked for
+                    if object.desc.kind == Kind::Blob {
+                        return true;
+                    }
+                    match io_service::read_object_from_file(
                         Arc::clone(&file),
-                        object.1.desc.offset,
-                        object.1.desc.size,
-                    );
-                    object.1.data = data.unwrap();
+                        object.desc.offset,
+                        object.desc.size,
+                    ) {
+                        Ok(data) => {
+                            object.data = data;
+                            true
+                        }
+                        Err(e) => {
+                            eprintln!("Dropping object '{}' with corrupted data: {}", key, e);
+                            false
+                        }
+                    }
+                });
+                if let Ok(index) = self.key_index.get_mut() {
+                    index.keys.retain(|key| map.contains_key(key));
+                    index.versions.retain(|(_, key)| map.contains_key(key));
                 }
             }
             Err(e) => {
@@ -185,40 +407,58 @@ impl ObjectService {
         }
         println!("Loaded object data");
     }
-    pub fn get_data(&self, key: &str) -> Option<Vec<u8>> {
+    pub fn get_desc(&self, key: &str) -> Option<ObjectDescriptor> {
         let map = self.objects_map.read();
+        let now = expiry::now_millis();
         match map {
             Ok(map) => match map.get(key) {
-                Some(object) => Some(object.data.clone()),
-                None => None,
+                Some(object) if !object.desc.is_expired(now) => Some(object.desc.clone()),
+                _ => None,
             },
             Err(_) => None,
         }
     }
-    pub fn get_desc(&self, key: &str) -> Option<ObjectDescriptor> {
-        let map = self.objects_map.read();
-        match map {
-            Ok(map) => match map.get(key) {
-                Some(object) => Some(object.desc.clone()),
-                None => None,
-            },
-            Err(_) => None,
+    pub fn is_expired(&self, key: &str, now: u64) -> bool {
+        match self.objects_map.read() {
+            Ok(map) => map.get(key).is_some_and(|obj| obj.desc.is_expired(now)),
+            Err(_) => false,
         }
     }
     pub fn get_object(&self, key: &str) -> Option<Object> {
         let map = self.objects_map.read();
+        let now = expiry::now_millis();
         match map {
             Ok(map) => match map.get(key) {
-                Some(object) => Some(object.clone()),
-                None => None,
+                Some(object) if !object.desc.is_expired(now) => Some(object.clone()),
+                _ => None,
             },
             Err(_) => None,
         }
     }
+    /// Runs `f` on the descriptor of a live key with the map read-locked,
+    /// for readers that open the data file themselves.
+    pub fn with_desc<F, T>(&self, key: &str, f: F) -> Option<T>
+    where
+        F: FnOnce(&ObjectDescriptor) -> T,
+    {
+        let map = self.objects_map.read().ok()?;
+        let object = map.get(key)?;
+        if object.desc.is_expired(expiry::now_millis()) {
+            return None;
+        }
+        Some(f(&object.desc))
+    }
     pub fn set(&self, object: Object) -> Result<(), Box<dyn Error>> {
         match self.objects_map.write() {
             Ok(mut map) => {
-                map.insert(object.desc.key.to_string(), object);
+                let key = object.desc.key.to_string();
+                let version = object.desc.version;
+                let previous = map.insert(key.clone(), object);
+                self.key_index.write().unwrap().insert(
+                    &key,
+                    version,
+                    previous.map(|object| object.desc.version),
+                );
                 Ok(())
             }
             Err(e) => {
@@ -227,14 +467,20 @@ impl ObjectService {
             }
         }
     }
-    pub fn delete(&self, key: String) -> Result<Object, Box<dyn Error + Send + Sync>> {
+    pub fn update_desc(
+        &self,
+        key: &str,
+        desc: ObjectDescriptor,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
         match self.objects_map.write() {
             Ok(mut map) => {
-                let obj = map.get(&key).ok_or("Object not found")?;
-                let mut obj_copy = obj.clone();
-                obj_copy.desc.is_deleted = true;
-                map.remove(&key);
-                Ok(obj_copy)
+                let obj = map.get_mut(key).ok_or("Object not found")?;
+                self.key_index
+                    .write()
+                    .unwrap()
+                    .insert(key, desc.version, Some(obj.desc.version));
+                obj.desc = desc;
+                Ok(())
             }
             Err(e) => {
                 let msg = format!("Poisoned lock: {}", e);
@@ -242,21 +488,29 @@ impl ObjectService {
             }
         }
     }
-    pub fn list(&self) -> Result<Vec<ObjectListElement>, Box<dyn Error + Send + Sync>> {
-        match self.objects_map.read() {
-            Ok(map) => {
-                let mut list: Vec<ObjectListElement> = map
-                    .iter()
-                    .map(|(key, obj)| ObjectListElement {
-                        key: key.clone(),
-                        kind: obj.desc.kind.clone(),
-                        size: obj.desc.size,
-                        offset: obj.desc.offset,
-                    })
-                    .collect();
-
-                list.sort_by_key(|elem| elem.offset);
-                Ok(list)
+    /// Applies a committed batch under one lock, `None` removing the key.
+    pub fn apply(
+        &self,
+        batch: Vec<(String, Option<Object>)>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match self.objects_map.write() {
+            Ok(mut map) => {
+                let mut index = self.key_index.write().unwrap();
+                for (key, object) in batch {
+                    match object {
+                        Some(object) => {
+                            let version = object.desc.version;
+                            let previous = map.insert(key.clone(), object);
+                            index.insert(&key, version, previous.map(|object| object.desc.version));
+                        }
+                        None => {
+                            if let Some(object) = map.remove(&key) {
+                                index.remove(&key, object.desc.version);
+                            }
+                        }
+                    }
+                }
+                Ok(())
             }
             Err(e) => {
                 let msg = format!("Poisoned lock: {}", e);
@@ -264,25 +518,18 @@ impl ObjectService {
             }
         }
     }
-    pub fn list_by_kind(
-        &self,
-        kind: Kind,
-    ) -> Result<Vec<ObjectListElement>, Box<dyn Error + Send + Sync>> {
-        match self.objects_map.read() {
-            Ok(map) => {
-                let mut list: Vec<ObjectListElement> = map
-                    .iter()
-                    .filter(|(_, obj)| obj.desc.kind == kind)
-                    .map(|(key, obj)| ObjectListElement {
-                        key: key.clone(),
-                        kind: obj.desc.kind.clone(),
-                        size: obj.desc.size,
-                        offset: obj.desc.offset,
-                    })
-                    .collect();
-
-                list.sort_by_key(|elem| elem.offset);
-                Ok(list)
+    pub fn delete(&self, key: String) -> Result<Object, Box<dyn Error + Send + Sync>> {
+        match self.objects_map.write() {
+            Ok(mut map) => {
+                let obj = map.get(&key).ok_or("Object not found")?;
+                let mut obj_copy = obj.clone();
+                obj_copy.desc.is_deleted = true;
+                map.remove(&key);
+                self.key_index
+                    .write()
+                    .unwrap()
+                    .remove(&key, obj_copy.desc.version);
+                Ok(obj_copy)
             }
             Err(e) => {
                 let msg = format!("Poisoned lock: {}", e);
@@ -290,4 +537,273 @@ impl ObjectService {
             }
         }
     }
+    pub fn expired_keys(&self, now: u64) -> Vec<String> {
+        match self.objects_map.read() {
+            Ok(map) => map
+                .iter()
+                .filter(|(_, obj)| obj.desc.is_expired(now))
+                .map(|(key, _)| key.clone())
+                .collect(),
+            Err(_) => vec![],
+        }
+    }
+    /// Pages through live entries in (version, key) order, starting after
+    /// `after`. Without a limit everything that follows is returned.
+    pub fn list(
+        &self,
+        filter: &ListFilter,
+        after: Option<&ListCursor>,
+        limit: Option<usize>,
+    ) -> Result<ListPage, Box<dyn Error + Send + Sync>> {
+        let now = expiry::now_millis();
+        let map = self
+            .objects_map
+            .read()
+            .map_err(|e| format!("Poisoned lock: {}", e))?;
+        let index = self
+            .key_index
+            .read()
+            .map_err(|e| format!("Poisoned lock: {}", e))?;
+
+        let start = match after {
+            Some(after) => Bound::Excluded((after.version, after.key.clone())),
+            None => Bound::Unbounded,
+        };
+        let limit = limit.unwrap_or(usize::MAX);
+        let mut items = Vec::new();
+        let mut entries = index
+            .versions
+            .range((start, Bound::Unbounded))
+            .filter_map(|(_, key)| map.get(key).map(|obj| (key, obj)))
+            .filter(|(key, obj)| !obj.desc.is_expired(now) && filter.matches(key, obj));
+        for (key, obj) in entries.by_ref().take(limit) {
+            items.push(ObjectListElement {
+                key: key.clone(),
+                kind: obj.desc.kind.clone(),
+                size: obj.desc.size,
+                version: obj.desc.version,
+            });
+        }
+        // A cursor only when another entry follows, so the last page is
+        // never an empty one
+        let cursor = match entries.next() {
+            Some(_) => items.last().map(|last| ListCursor {
+                version: last.version,
+                key: last.key.clone(),
+            }),
+            None => None,
+        };
+        Ok(ListPage { items, cursor })
+    }
+    /// Walks keys in order and returns at most `limit` live entries under
+    /// `key_prefixes`.
+    pub fn scan(
+        &self,
+        range: &ScanRange,
+        limit: Option<usize>,
+        key_prefixes: &[String],
+    ) -> Result<Vec<ObjectListElement>, Box<dyn Error + Send + Sync>> {
+        let now = expiry::now_millis();
+        let map = self
+            .objects_map
+            .read()
+            .map_err(|e| format!("Poisoned lock: {}", e))?;
+        let index = self
+            .key_index
+            .read()
+            .map_err(|e| format!("Poisoned lock: {}", e))?;
+
+        let (start, end) = match range {
+            ScanRange::Prefix(prefix) => (prefix.as_str(), None),
+            ScanRange::Range { start, end } => (start.as_str(), end.as_deref()),
+        };
+        let end = match end {
+            // `range` panics on reversed bounds
+            Some(end) if end < start => return Ok(Vec::new()),
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        let list = index
+            .keys
+            .range::<str, _>((Bound::Included(start), end))
+            .take_while(|key| match range {
+                ScanRange::Prefix(prefix) => key.starts_with(prefix.as_str()),
+                ScanRange::Range { .. } => true,
+            })
+            .filter_map(|key| map.get(key).map(|obj| (key, obj)))
+            .filter(|(key, obj)| !obj.desc.is_expired(now) && under_prefixes(key, key_prefixes))
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|(key, obj)| ObjectListElement {
+                key: key.clone(),
+                kind: obj.desc.kind.clone(),
+                size: obj.desc.size,
+                version: obj.desc.version,
+            })
+            .collect();
+        Ok(list)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_round_trip_as_utf8() {
+        assert_eq!(Key256::new("zażółć").to_string(), "zażółć");
+        assert_eq!(
+            Key256::new(&"k".repeat(MAX_KEY_LEN)).to_string().len(),
+            MAX_KEY_LEN
+        );
+    }
+
+    fn service(keys: &[&str]) -> ObjectService {
+        let objects = ObjectService::new();
+        for (version, key) in keys.iter().enumerate() {
+            put(&objects, key, version as u64 + 1);
+        }
+        objects
+    }
+
+    fn put(objects: &ObjectService, key: &str, version: u64) {
+        let desc = ObjectDescriptor {
+            key: Key256::new(key),
+            kind: Kind::String,
+            offset: 0,
+            size: 1,
+            is_deleted: false,
+            expires_at: 0,
+            version,
+            txn_id: 0,
+            is_commit: false,
+            desc_offset: 0,
+        };
+        objects
+            .set(Object {
+                desc,
+                data: b"v".to_vec(),
+            })
+            .unwrap();
+    }
+
+    fn scan_keys(objects: &ObjectService, start: &str, end: Option<&str>) -> Vec<String> {
+        let range = ScanRange::Range {
+            start: start.to_string(),
+            end: end.map(str::to_string),
+        };
+        objects
+            .scan(&range, None, &[])
+            .unwrap()
+            .into_iter()
+            .map(|elem| elem.key)
+            .collect()
+    }
+
+    #[test]
+    fn scan_range_is_half_open() {
+        let objects = service(&["a", "b", "c"]);
+        assert_eq!(scan_keys(&objects, "a", Some("c")), ["a", "b"]);
+        assert_eq!(scan_keys(&objects, "b", None), ["b", "c"]);
+        assert!(scan_keys(&objects, "b", Some("b")).is_empty());
+    }
+
+    #[test]
+    fn scan_reversed_range_is_empty() {
+        let objects = service(&["a", "b", "c"]);
+        assert!(scan_keys(&objects, "c", Some("a")).is_empty());
+    }
+
+    fn page_keys(page: &ListPage) -> Vec<&str> {
+        page.items.iter().map(|elem| elem.key.as_str()).collect()
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let cursor = ListCursor {
+            version: 0x2a,
+            key: "user:ü/1".to_string(),
+        };
+        assert_eq!(cursor.to_string().parse::<ListCursor>(), Ok(cursor));
+        assert!("2a".parse::<ListCursor>().is_err());
+        assert!("2a.abc".parse::<ListCursor>().is_err());
+        assert!("zz.61".parse::<ListCursor>().is_err());
+        assert!("2a.ff".parse::<ListCursor>().is_err());
+    }
+
+    fn page(objects: &ObjectService, after: Option<&ListCursor>, limit: usize) -> ListPage {
+        objects
+            .list(&ListFilter::default(), after, Some(limit))
+            .unwrap()
+    }
+
+    #[test]
+    fn pages_cover_every_entry_once() {
+        let objects = service(&["k1", "k2", "k3", "k4", "k5"]);
+
+        let first = page(&objects, None, 2);
+        assert_eq!(page_keys(&first), ["k1", "k2"]);
+        let second = page(&objects, first.cursor.as_ref(), 2);
+        assert_eq!(page_keys(&second), ["k3", "k4"]);
+        let last = page(&objects, second.cursor.as_ref(), 2);
+        assert_eq!(page_keys(&last), ["k5"]);
+        assert!(last.cursor.is_none());
+
+        let all = objects.list(&ListFilter::default(), None, None).unwrap();
+        assert_eq!(all.items.len(), 5);
+        assert!(all.cursor.is_none());
+    }
+
+    #[test]
+    fn exact_last_page_has_no_cursor() {
+        let objects = service(&["a", "b"]);
+        let last = page(&objects, None, 2);
+        assert_eq!(page_keys(&last), ["a", "b"]);
+        assert!(last.cursor.is_none());
+    }
+
+    #[test]
+    fn same_version_is_ordered_by_key() {
+        // Keys restored with one version still page in a stable order
+        let objects = ObjectService::new();
+        for key in ["c", "a", "b"] {
+            put(&objects, key, 7);
+        }
+        let first = page(&objects, None, 1);
+        let rest = objects
+            .list(&ListFilter::default(), first.cursor.as_ref(), None)
+            .unwrap();
+        assert_eq!(page_keys(&rest), ["b", "c"]);
+    }
+
+    #[test]
+    fn rewritten_key_moves_to_the_end() {
+        let objects = service(&["a", "b", "c"]);
+        let first = page(&objects, None, 2);
+        put(&objects, "a", 4);
+        objects.delete("b".to_string()).unwrap();
+        let rest = page(&objects, first.cursor.as_ref(), 10);
+        assert_eq!(page_keys(&rest), ["c", "a"]);
+    }
+
+    #[test]
+    fn key_prefixes_apply_before_the_limit() {
+        let objects = service(&["a:1", "b:1", "b:2", "a:2", "b:3", "a:3"]);
+        let prefixes = ["a:".to_string()];
+        let filter = ListFilter {
+            key_prefixes: &prefixes,
+            ..Default::default()
+        };
+        let first = objects.list(&filter, None, Some(2)).unwrap();
+        assert_eq!(page_keys(&first), ["a:1", "a:2"]);
+        let last = objects
+            .list(&filter, first.cursor.as_ref(), Some(2))
+            .unwrap();
+        assert_eq!(page_keys(&last), ["a:3"]);
+        assert!(last.cursor.is_none());
+
+        let range = ScanRange::Prefix(String::new());
+        let scanned = objects.scan(&range, Some(2), &prefixes).unwrap();
+        let keys: Vec<_> = scanned.iter().map(|elem| elem.key.as_str()).collect();
+        assert_eq!(keys, ["a:1", "a:2"]);
+    }
 }
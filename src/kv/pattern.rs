@@ -0,0 +1,179 @@
+use regex::Regex;
+
+/// Key filter for listings: a Redis style glob, a regular expression or a
+/// plain prefix.
+#[derive(Debug, Clone)]
+pub enum KeyPattern {
+    Glob(String),
+    Regex(Regex),
+    Prefix(String),
+}
+
+impl KeyPattern {
+    pub fn glob(pattern: &str) -> KeyPattern {
+        KeyPattern::Glob(pattern.to_string())
+    }
+
+    pub fn regex(pattern: &str) -> Result<KeyPattern, String> {
+        Regex::new(pattern)
+            .map(KeyPattern::Regex)
+            .map_err(|e| format!("Invalid regex: {}", e))
+    }
+
+    pub fn prefix(prefix: &str) -> KeyPattern {
+        KeyPattern::Prefix(prefix.to_string())
+    }
+
+    pub fn matches(&self, key: &str) -> bool {
+        match self {
+            KeyPattern::Glob(pattern) => glob_match(pattern.as_bytes(), key.as_bytes()),
+            KeyPattern::Regex(regex) => regex.is_match(key),
+            KeyPattern::Prefix(prefix) => key.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+// Supports `*`, `?`, `[abc]`, `[a-z]`, `[^a]` / `[!a]` and `\` escapes,
+// matching bytes like Redis KEYS does.
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // Where to resume after the last `*` when the rest fails to match
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while k < key.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    backtrack = Some((p, k));
+                    p += 1;
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    k += 1;
+                    continue;
+                }
+                b'[' => {
+                    if let Some((matched, next)) = match_class(pattern, p, key[k])
+                        && matched
+                    {
+                        p = next;
+                        k += 1;
+                        continue;
+                    }
+                }
+                b'\\' if p + 1 < pattern.len() && pattern[p + 1] == key[k] => {
+                    p += 2;
+                    k += 1;
+                    continue;
+                }
+                b'\\' if p + 1 < pattern.len() => {}
+                c if c == key[k] => {
+                    p += 1;
+                    k += 1;
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        match backtrack {
+            Some((star, start)) => {
+                p = star + 1;
+                k = start + 1;
+                backtrack = Some((star, start + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+// Returns whether `c` is in the class starting at `pattern[start] == '['`
+// and the index right after the closing `]`, or `None` if it is unclosed.
+fn match_class(pattern: &[u8], start: usize, c: u8) -> Option<(bool, usize)> {
+    let mut i = start + 1;
+    let negated = matches!(pattern.get(i), Some(b'^') | Some(b'!'));
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    while i < pattern.len() && (pattern[i] != b']' || first) {
+        first = false;
+        let mut low = pattern[i];
+        if low == b'\\' && i + 1 < pattern.len() {
+            i += 1;
+            low = pattern[i];
+        }
+        if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+            let high = pattern[i + 2];
+            if low <= c && c <= high {
+                matched = true;
+            }
+            i += 3;
+        } else {
+            if low == c {
+                matched = true;
+            }
+            i += 1;
+        }
+    }
+    if i >= pattern.len() {
+        return None;
+    }
+    Some((matched != negated, i + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(pattern: &str, key: &str) -> bool {
+        KeyPattern::glob(pattern).matches(key)
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        assert!(glob("*", ""));
+        assert!(glob("*", "anything"));
+        assert!(glob("user:*", "user:1"));
+        assert!(!glob("user:*", "users:1"));
+        assert!(glob("cache:*:v2", "cache:a:b:v2"));
+        assert!(!glob("cache:*:v2", "cache:a:v3"));
+        assert!(glob("h?llo", "hello"));
+        assert!(!glob("h?llo", "hllo"));
+        assert!(glob("*a*b", "xxaxxb"));
+        assert!(!glob("*a*b", "xxaxxbx"));
+    }
+
+    #[test]
+    fn glob_classes() {
+        assert!(glob("h[ae]llo", "hallo"));
+        assert!(!glob("h[ae]llo", "hillo"));
+        assert!(glob("key[0-9]", "key7"));
+        assert!(!glob("key[0-9]", "keyx"));
+        assert!(glob("h[^e]llo", "hallo"));
+        assert!(!glob("h[!e]llo", "hello"));
+        assert!(glob("[]]", "]"));
+        // An unclosed class matches nothing
+        assert!(!glob("h[ello", "hello"));
+    }
+
+    #[test]
+    fn glob_escapes() {
+        assert!(glob("a\\*b", "a*b"));
+        assert!(!glob("a\\*b", "axb"));
+        assert!(glob("what\\?", "what?"));
+        assert!(glob("[\\]]", "]"));
+    }
+
+    #[test]
+    fn prefix_and_regex() {
+        assert!(KeyPattern::prefix("user-").matches("user-1"));
+        assert!(!KeyPattern::prefix("user-").matches("use"));
+        let regex = KeyPattern::regex("^a[0-9]+$").unwrap();
+        assert!(regex.matches("a12"));
+        assert!(!regex.matches("a1b"));
+        assert!(KeyPattern::regex("(").is_err());
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+};
+
+use crate::kv::{
+    io_service as io,
+    objects::{ObjectDescriptor, RECORD_SIZE},
+};
+
+/// Cuts off whatever a crash left half-written at the end of the files:
+/// a partial descriptor record, and data appended after the last descriptor
+/// that made it to disk. Complete records are kept even when they don't
+/// verify; replay skips and reports them. Expects both files to have passed
+/// their header check.
+pub fn truncate_torn_tails(data_file: &mut File, desc_file: &mut File) -> std::io::Result<()> {
+    let desc_len = desc_file.metadata()?.len();
+    let record_size = RECORD_SIZE as u64;
+    let complete_end =
+        io::FILE_HEADER_SIZE + (desc_len - io::FILE_HEADER_SIZE) / record_size * record_size;
+    let mut buffer = vec![0u8; RECORD_SIZE];
+    let mut offset = io::FILE_HEADER_SIZE;
+    let mut data_end = io::FILE_HEADER_SIZE;
+    let mut unreadable = 0;
+
+    desc_file.seek(SeekFrom::Start(offset))?;
+    while offset < complete_end {
+        desc_file.read_exact(&mut buffer)?;
+        offset += record_size;
+        match io::verify_desc(&buffer)
+            .and_then(|record| bincode::deserialize::<ObjectDescriptor>(record).ok())
+        {
+            Some(desc) => data_end = data_end.max(desc.offset + io::HEADER_SIZE + desc.size),
+            None => unreadable += 1,
+        }
+    }
+    desc_file.seek(SeekFrom::Start(io::FILE_HEADER_SIZE))?;
+
+    if complete_end < desc_len {
+        eprintln!(
+            "Recovery: dropped {} byte(s) of a partial descriptor record",
+            desc_len - complete_end
+        );
+        desc_file.set_len(complete_end)?;
+        desc_file.sync_all()?;
+    }
+
+    // The data an unreadable descriptor points at is unknown, so the data
+    // tail is only cut when every descriptor could be read
+    let data_len = data_file.metadata()?.len();
+    if unreadable > 0 {
+        if data_end < data_len {
+            eprintln!(
+                "Recovery: kept {} byte(s) at the end of the data file, {} descriptor(s) can't be read",
+                data_len - data_end,
+                unreadable
+            );
+        }
+    } else if data_end < data_len {
+        eprintln!(
+            "Recovery: dropped {} byte(s) of data not referenced by any descriptor",
+            data_len - data_end
+        );
+        data_file.set_len(data_end)?;
+        data_file.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::OpenOptions,
+        io::Write,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::kv::objects::{Key256, Kind};
+
+    struct Files {
+        data: String,
+        desc: String,
+    }
+
+    impl Files {
+        fn new(dir: &tempfile::TempDir) -> Files {
+            let dir = dir.path().to_str().unwrap();
+            let files = Files {
+                data: io::get_data_filename(dir, "test"),
+                desc: io::get_desc_filename(dir, "test"),
+            };
+            io::open_data_file(&files.data).unwrap();
+            io::open_desc_file(&files.desc).unwrap();
+            files
+        }
+
+        fn write(&self, key: &str, value: &[u8]) {
+            let data = Arc::new(Mutex::new(io::open_data_file(&self.data).unwrap()));
+            let desc = Arc::new(Mutex::new(io::open_desc_file(&self.desc).unwrap()));
+            let offset = io::save_object_in_file(&value.to_vec(), data).unwrap();
+            let record = ObjectDescriptor {
+                key: Key256::new(key),
+                kind: Kind::String,
+                offset,
+                size: value.len() as u64,
+                is_deleted: false,
+                expires_at: 0,
+                version: 1,
+                txn_id: 0,
+                is_commit: false,
+                desc_offset: 0,
+            };
+            io::save_desc_in_file(bincode::serialize(&record).unwrap(), desc).unwrap();
+        }
+
+        fn append(path: &str, bytes: &[u8]) {
+            let mut file = OpenOptions::new().append(true).open(path).unwrap();
+            file.write_all(bytes).unwrap();
+        }
+
+        fn truncate(&self) {
+            let mut data = io::open_data_file(&self.data).unwrap();
+            let mut desc = io::open_desc_file(&self.desc).unwrap();
+            truncate_torn_tails(&mut data, &mut desc).unwrap();
+        }
+
+        fn lens(&self) -> (u64, u64) {
+            (
+                std::fs::metadata(&self.data).unwrap().len(),
+                std::fs::metadata(&self.desc).unwrap().len(),
+            )
+        }
+    }
+
+    #[test]
+    fn partial_descriptor_record_is_cut() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = Files::new(&dir);
+        files.write("a", b"one");
+        files.write("b", b"two");
+        let before = files.lens();
+
+        Files::append(&files.desc, &[7u8; 10]);
+        files.truncate();
+        assert_eq!(files.lens(), before);
+    }
+
+    #[test]
+    fn unreadable_complete_records_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = Files::new(&dir);
+        files.write("a", b"one");
+        files.write("b", b"two");
+        // Data without a descriptor, normally cut as a torn tail
+        Files::append(&files.data, b"orphan");
+        let corrupt_at = io::FILE_HEADER_SIZE + RECORD_SIZE as u64 + 5;
+        let desc = OpenOptions::new().write(true).open(&files.desc).unwrap();
+        std::os::unix::fs::FileExt::write_all_at(&desc, &[0xff], corrupt_at).unwrap();
+        let before = files.lens();
+
+        files.truncate();
+        assert_eq!(files.lens(), before);
+    }
+
+    #[test]
+    fn unreferenced_data_is_cut() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = Files::new(&dir);
+        files.write("a", b"one");
+        let before = files.lens();
+
+        Files::append(&files.data, b"written before a crash");
+        files.truncate();
+        assert_eq!(files.lens(), before);
+    }
+
+    #[test]
+    fn empty_files_keep_their_headers() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = Files::new(&dir);
+
+        files.truncate();
+        assert_eq!(files.lens(), (io::FILE_HEADER_SIZE, io::FILE_HEADER_SIZE));
+    }
+}
@@ -0,0 +1,49 @@
+use crate::kv::{core::SetOptions, objects::Kind};
+
+pub enum TxnOp {
+    Set {
+        key: String,
+        kind: Kind,
+        data: Vec<u8>,
+        options: SetOptions,
+    },
+    Delete {
+        key: String,
+    },
+}
+
+/// Sets and deletes staged in memory. Nothing touches the files until
+/// `Core::commit` writes them all, followed by a commit marker.
+#[derive(Default)]
+pub struct Transaction {
+    pub ops: Vec<TxnOp>,
+}
+
+impl Transaction {
+    pub fn new() -> Transaction {
+        Transaction::default()
+    }
+
+    pub fn set(&mut self, key: &str, kind: Kind, data: Vec<u8>, options: SetOptions) {
+        self.ops.push(TxnOp::Set {
+            key: key.to_string(),
+            kind,
+            data,
+            options,
+        });
+    }
+
+    pub fn delete(&mut self, key: &str) {
+        self.ops.push(TxnOp::Delete {
+            key: key.to_string(),
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
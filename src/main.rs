@@ -1,10 +1,18 @@
-use std::sync::Arc;
+use std::{error::Error, sync::Arc};
 
+use auth::Acl;
+use config::Config;
+use kv::databases::Databases;
+use shutdown::Shutdown;
+
+mod auth;
+mod config;
 mod http_service;
 mod js;
 mod kv;
-
-const DIR_PATH: &str = "./humpback-data";
+mod resp_service;
+mod shutdown;
+mod tcp_service;
 
 fn main() {
     println!(
@@ -19,12 +27,76 @@ fn main() {
         "#
     );
 
-    let kv = kv::core::Core::new().expect("Init error");
+    let config = match config::Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Config error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    println!("Data directory: {}", config.data_dir);
+    println!("Durability mode: {}", config.storage.durability);
 
-    match http_service::run(Arc::clone(&kv)) {
+    let dbs =
+        Databases::new(config.core_options(), config.storage.max_databases).expect("Init error");
+
+    let rt = tokio::runtime::Runtime::new().expect("Failed to build Tokio runtime");
+    match rt.block_on(serve(dbs, config)) {
         Ok(_) => {}
         Err(e) => {
             println!("{}", e);
         }
     }
 }
+
+// Runs the HTTP, TCP and RESP front-ends over the same databases and JS runtime
+// until ctrl+c or until one of them fails, then flushes everything once all
+// of them have stopped.
+async fn serve(dbs: Arc<Databases>, config: Config) -> Result<(), Box<dyn Error>> {
+    let runtime = js::runtime::Runtime::new(Arc::clone(&dbs), &config.js);
+    let acl = Acl::new(&config.auth, &dbs).map_err(|e| format!("Auth init error: {}", e))?;
+    let shutdown = Shutdown::listen();
+
+    let results = tokio::join!(
+        stop_all_on_error(
+            http_service::run(
+                Arc::clone(&dbs),
+                Arc::clone(&runtime),
+                Arc::clone(&acl),
+                &config,
+                shutdown.clone()
+            ),
+            &shutdown
+        ),
+        stop_all_on_error(
+            tcp_service::run(
+                Arc::clone(&dbs),
+                Arc::clone(&runtime),
+                Arc::clone(&acl),
+                &config,
+                shutdown.clone()
+            ),
+            &shutdown
+        ),
+        stop_all_on_error(
+            resp_service::run(Arc::clone(&dbs), acl, &config, shutdown.clone()),
+            &shutdown
+        ),
+    );
+
+    dbs.sync()?;
+    println!("All data flushed to disk");
+    println!("Resources released");
+    results.0.and(results.1).and(results.2)
+}
+
+async fn stop_all_on_error(
+    server: impl Future<Output = Result<(), Box<dyn Error>>>,
+    shutdown: &Shutdown,
+) -> Result<(), Box<dyn Error>> {
+    let result = server.await;
+    if result.is_err() {
+        shutdown.trigger();
+    }
+    result
+}
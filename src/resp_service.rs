@@ -0,0 +1,737 @@
+use crate::{
+    auth::{Acl, Principal, Scope},
+    config::Config,
+    kv::{
+        core::{Condition, Core, ListOptions, SetOptions},
+        databases::Databases,
+        expiry,
+        objects::{Kind, ListCursor, ListFilter, Object},
+        pattern::KeyPattern,
+    },
+    shutdown::Shutdown,
+};
+use std::{error::Error, str::FromStr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    net::{TcpListener, TcpStream},
+    task::JoinSet,
+};
+
+const DEFAULT_SCAN_COUNT: usize = 10;
+// Inline commands and the length lines of arrays and bulk strings
+const MAX_LINE_SIZE: usize = 64 * 1024;
+
+/// How much a client may send in one command.
+struct Limits {
+    args: usize,
+    bulk_size: usize,
+}
+
+// Same cap as the largest value kind, anything bigger is a broken client
+const AUTHENTICATED: Limits = Limits {
+    args: 1024 * 1024,
+    bulk_size: 1024 * 1024 * 20,
+};
+// Enough for `HELLO 3 AUTH <user> <password> SETNAME <name>`, so clients
+// that didn't log in yet can't make the server allocate much
+const UNAUTHENTICATED: Limits = Limits {
+    args: 16,
+    bulk_size: 4096,
+};
+
+/// Redis compatible front-end, so `redis-cli` and Redis client libraries
+/// can talk to Humpback. Speaks RESP2 until a client switches to RESP3
+/// with `HELLO 3`.
+pub async fn run(
+    dbs: Arc<Databases>,
+    acl: Arc<Acl>,
+    config: &Config,
+    mut shutdown: Shutdown,
+) -> Result<(), Box<dyn Error>> {
+    let Some(resp) = &config.resp else {
+        return Ok(());
+    };
+    let listener = TcpListener::bind(&resp.listen).await?;
+    println!("RESP server is listening on {}", resp.listen);
+
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            Ok((socket, addr)) = listener.accept() => {
+                println!("New RESP connection from: {}", addr);
+                let dbs = Arc::clone(&dbs);
+                let acl = Arc::clone(&acl);
+                let shutdown = shutdown.clone();
+                connections.spawn(async move {
+                    if let Err(e) = handle_client(socket, dbs, acl, shutdown).await {
+                        eprintln!("RESP connection error: {}", e);
+                    }
+                });
+            }
+            Some(_) = connections.join_next() => {}
+            _ = shutdown.recv() => {
+                break;
+            }
+        }
+    }
+    while connections.join_next().await.is_some() {}
+
+    println!("RESP server stopped");
+    Ok(())
+}
+
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Reply>),
+    // Sent as a flat array to RESP2 clients
+    Map(Vec<(Reply, Reply)>),
+}
+
+impl Reply {
+    fn ok() -> Reply {
+        Reply::simple("OK")
+    }
+
+    fn simple(value: &str) -> Reply {
+        Reply::Simple(value.to_string())
+    }
+
+    fn err(message: impl Into<String>) -> Reply {
+        Reply::Error(format!("ERR {}", message.into()))
+    }
+
+    fn bulk(value: impl Into<Vec<u8>>) -> Reply {
+        Reply::Bulk(value.into())
+    }
+
+    fn encode(&self, protocol: u8, out: &mut Vec<u8>) {
+        match self {
+            Reply::Simple(s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            Reply::Error(e) => {
+                // A newline would end the error early
+                let e = e.replace(['\r', '\n'], " ");
+                out.extend_from_slice(format!("-{}\r\n", e).as_bytes());
+            }
+            Reply::Integer(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            Reply::Bulk(data) => {
+                out.extend_from_slice(format!("${}\r\n", data.len()).as_bytes());
+                out.extend_from_slice(data);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Null if protocol >= 3 => out.extend_from_slice(b"_\r\n"),
+            Reply::Null => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(protocol, out);
+                }
+            }
+            Reply::Map(entries) => {
+                let header = match protocol {
+                    3.. => format!("%{}\r\n", entries.len()),
+                    _ => format!("*{}\r\n", entries.len() * 2),
+                };
+                out.extend_from_slice(header.as_bytes());
+                for (key, value) in entries {
+                    key.encode(protocol, out);
+                    value.encode(protocol, out);
+                }
+            }
+        }
+    }
+}
+
+// Per connection state
+struct Session {
+    core: Arc<Core>,
+    // Set by AUTH or HELLO AUTH, most commands are refused until then
+    principal: Option<Arc<Principal>>,
+    protocol: u8,
+    quit: bool,
+}
+
+impl Session {
+    fn limits(&self) -> &'static Limits {
+        match self.principal {
+            Some(_) => &AUTHENTICATED,
+            None => &UNAUTHENTICATED,
+        }
+    }
+}
+
+async fn handle_client(
+    socket: TcpStream,
+    dbs: Arc<Databases>,
+    acl: Arc<Acl>,
+    mut shutdown: Shutdown,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (reader, writer) = socket.into_split();
+    let mut buf_reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let mut session = Session {
+        core: dbs.main(),
+        principal: None,
+        protocol: 2,
+        quit: false,
+    };
+    let mut out = Vec::new();
+    loop {
+        // Replies to pipelined commands are written together
+        if buf_reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+        let command = tokio::select! {
+            command = read_command(&mut buf_reader, session.limits()) => command?,
+            _ = shutdown.recv() => break,
+        };
+        let args = match command {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(e) => {
+                // The stream can't be trusted after a framing error
+                out.clear();
+                Reply::Error(format!("ERR Protocol error: {}", e))
+                    .encode(session.protocol, &mut out);
+                writer.write_all(&out).await?;
+                break;
+            }
+        };
+        if args.is_empty() {
+            continue;
+        }
+
+        let reply = execute(&mut session, &dbs, &acl, &args).await;
+        out.clear();
+        reply.encode(session.protocol, &mut out);
+        writer.write_all(&out).await?;
+        if session.quit {
+            break;
+        }
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+// Reads either a RESP array of bulk strings, which is what clients send,
+// or an inline command typed by hand, e.g. over telnet. The outer result
+// is for I/O errors, the inner one for malformed input.
+async fn read_command<R>(
+    reader: &mut R,
+    limits: &Limits,
+) -> std::io::Result<Result<Option<Vec<Vec<u8>>>, String>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let line = match read_line(reader).await? {
+        Some(Ok(line)) => line,
+        Some(Err(e)) => return Ok(Err(e)),
+        None => return Ok(Ok(None)),
+    };
+    let Some(count) = line.strip_prefix(b"*") else {
+        let args = line
+            .split(|c| c.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| arg.to_vec())
+            .collect();
+        return Ok(Ok(Some(args)));
+    };
+    let count = match parse_len(count, limits.args) {
+        Ok(count) => count,
+        Err(e) => return Ok(Err(e)),
+    };
+
+    let mut args = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let line = match read_line(reader).await? {
+            Some(Ok(line)) => line,
+            Some(Err(e)) => return Ok(Err(e)),
+            None => return Ok(Ok(None)),
+        };
+        let Some(len) = line.strip_prefix(b"$") else {
+            return Ok(Err("expected '$'".to_string()));
+        };
+        let len = match parse_len(len, limits.bulk_size) {
+            Ok(len) => len,
+            Err(e) => return Ok(Err(e)),
+        };
+        let mut data = vec![0; len + 2];
+        reader.read_exact(&mut data).await?;
+        if !data.ends_with(b"\r\n") {
+            return Ok(Err("bulk string must end with CRLF".to_string()));
+        }
+        data.truncate(len);
+        args.push(data);
+    }
+    Ok(Ok(Some(args)))
+}
+
+// Reads at most `MAX_LINE_SIZE` bytes, a longer line is malformed input
+async fn read_line<R>(reader: &mut R) -> std::io::Result<Option<Result<Vec<u8>, String>>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = Vec::new();
+    let read = reader
+        .take(MAX_LINE_SIZE as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") && read == MAX_LINE_SIZE {
+        return Ok(Some(Err("line is too long".to_string())));
+    }
+    while line.last().is_some_and(|c| *c == b'\n' || *c == b'\r') {
+        line.pop();
+    }
+    Ok(Some(Ok(line)))
+}
+
+fn parse_len(bytes: &[u8], max: usize) -> Result<usize, String> {
+    let len = std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .ok_or("invalid length")?;
+    if len > max {
+        return Err("length is too large".to_string());
+    }
+    Ok(len)
+}
+
+async fn execute(session: &mut Session, dbs: &Databases, acl: &Acl, args: &[Vec<u8>]) -> Reply {
+    let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+    let mut strings = Vec::with_capacity(args.len() - 1);
+    // Keys and options must be text, only SET and MSET values may be binary
+    for (i, arg) in args[1..].iter().enumerate() {
+        match std::str::from_utf8(arg) {
+            Ok(s) => strings.push(s),
+            Err(_) if (name == "SET" && i == 1) || (name == "MSET" && i % 2 == 1) => {
+                strings.push("")
+            }
+            Err(_) => return Reply::err("Arguments must be valid UTF-8"),
+        }
+    }
+
+    match (name.as_str(), strings.as_slice()) {
+        ("AUTH", [token]) => return authenticate(session, acl.authenticate_token(token).await),
+        ("AUTH", [user, password]) => {
+            return authenticate(session, acl.login(user, password).await);
+        }
+        ("AUTH", _) => return Reply::err("wrong number of arguments for 'auth' command"),
+        ("HELLO", args) => return hello(session, acl, args).await,
+        ("QUIT", []) => {
+            session.quit = true;
+            return Reply::ok();
+        }
+        _ => {}
+    }
+    let Some(user) = session.principal.clone() else {
+        return Reply::Error("NOAUTH Authentication required.".to_string());
+    };
+    if let Some((scope, keys)) = required_access(&name, &strings) {
+        let denied = match keys.as_slice() {
+            [] => user.check(scope, None).err(),
+            keys => keys
+                .iter()
+                .find_map(|key| user.check(scope, Some(key)).err()),
+        };
+        if let Some(e) = denied {
+            return Reply::Error(e);
+        }
+    }
+    let core = Arc::clone(&session.core);
+
+    let start = std::time::Instant::now();
+    let reply = match (name.as_str(), strings.as_slice()) {
+        ("PING", []) => Reply::simple("PONG"),
+        ("PING", [message]) => Reply::bulk(*message),
+        ("ECHO", [message]) => Reply::bulk(*message),
+        ("SELECT", [db]) => {
+            // Redis clients select numbered databases, 0 being the default
+            let db = if *db == "0" { None } else { Some(*db) };
+            match dbs.get(db) {
+                Ok(selected) => {
+                    session.core = selected;
+                    Reply::ok()
+                }
+                Err(e) => Reply::err(e.to_string()),
+            }
+        }
+        ("GET", [key]) => match core.get_async(key).await {
+            Some(object) => Reply::Bulk(value_bytes(object)),
+            None => Reply::Null,
+        },
+        ("SET", [key, _, options @ ..]) => match parse_set_options(options) {
+            Ok(options) => {
+                let data = args[2].clone();
+                match core.set_async(key, Kind::String, data, options).await {
+                    Ok(Some(_)) => Reply::ok(),
+                    Ok(None) => Reply::Null,
+                    Err(e) => Reply::err(e.to_string()),
+                }
+            }
+            Err(e) => Reply::err(e),
+        },
+        ("MGET", keys) if !keys.is_empty() => {
+            let keys = keys.iter().map(|key| key.to_string()).collect();
+            let objects = core.get_many_async(keys).await;
+            Reply::Array(
+                objects
+                    .into_iter()
+                    .map(|object| match object {
+                        Some(object) => Reply::Bulk(value_bytes(object)),
+                        None => Reply::Null,
+                    })
+                    .collect(),
+            )
+        }
+        ("MSET", pairs) if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+            let entries = pairs
+                .iter()
+                .step_by(2)
+                .zip(args[2..].iter().step_by(2))
+                .map(|(key, value)| (key.to_string(), Kind::String, value.clone()))
+                .collect();
+            match core.set_many_async(entries).await {
+                Ok(_) => Reply::ok(),
+                Err(e) => Reply::err(e.to_string()),
+            }
+        }
+        ("DEL", keys) if !keys.is_empty() => {
+            let keys = keys.iter().map(|key| key.to_string()).collect();
+            match core.delete_many_async(keys).await {
+                Ok(deleted) => Reply::Integer(deleted as i64),
+                Err(e) => Reply::err(e.to_string()),
+            }
+        }
+        ("EXISTS", keys) if !keys.is_empty() => {
+            let found = keys
+                .iter()
+                .filter(|key| core.version(key).is_some())
+                .count();
+            Reply::Integer(found as i64)
+        }
+        ("INCR", [key]) => incr(&core, key, 1.0).await,
+        ("DECR", [key]) => incr(&core, key, -1.0).await,
+        ("INCRBY", [key, delta]) | ("DECRBY", [key, delta]) => match delta.parse::<i64>() {
+            Ok(delta) if name == "DECRBY" => incr(&core, key, -(delta as f64)).await,
+            Ok(delta) => incr(&core, key, delta as f64).await,
+            Err(_) => Reply::err("value is not an integer or out of range"),
+        },
+        // Like Redis, 0 expires the key at once
+        ("EXPIRE", [key, secs]) => match secs.parse::<u64>() {
+            Ok(secs) if Duration::from_secs(secs) > expiry::MAX_TTL => {
+                Reply::err("invalid expire time in 'expire' command")
+            }
+            Ok(secs) => {
+                let ttl = Some(Duration::from_secs(secs));
+                match core.set_expiry_async(key, ttl).await {
+                    Ok(_) => Reply::Integer(1),
+                    Err(_) => Reply::Integer(0),
+                }
+            }
+            Err(_) => Reply::err("value is not an integer or out of range"),
+        },
+        ("TTL", [key]) => match core.ttl(key) {
+            Some(Some(ttl)) => Reply::Integer(ttl.as_secs() as i64),
+            Some(None) => Reply::Integer(-1),
+            None => Reply::Integer(-2),
+        },
+        ("TYPE", [key]) => match core.get_async(key).await {
+            Some(object) => Reply::Simple(object.desc.kind.to_string()),
+            None => Reply::simple("none"),
+        },
+        ("KEYS", [pattern]) => {
+            let options = ListOptions {
+                pattern: Some(KeyPattern::glob(pattern)),
+                key_prefixes: user.key_prefixes().to_vec(),
+                ..Default::default()
+            };
+            match core.list(options).await {
+                Ok(page) => Reply::Array(
+                    page.items
+                        .into_iter()
+                        .map(|element| Reply::bulk(element.key))
+                        .collect(),
+                ),
+                Err(_) => Reply::err("Unable to list objects"),
+            }
+        }
+        ("SCAN", [cursor, options @ ..]) => scan(&core, &user, cursor, options).await,
+        ("INFO", [] | [_]) => info(session, dbs),
+        ("DBSIZE", []) => {
+            let options = ListOptions {
+                key_prefixes: user.key_prefixes().to_vec(),
+                ..Default::default()
+            };
+            match core.list(options).await {
+                Ok(page) => Reply::Integer(page.items.len() as i64),
+                Err(_) => Reply::err("Unable to list objects"),
+            }
+        }
+        // redis-cli asks for command docs on startup, an empty answer is fine
+        ("COMMAND", _) => Reply::Array(Vec::new()),
+        ("CLIENT", _) => Reply::ok(),
+        (
+            "PING" | "ECHO" | "SELECT" | "GET" | "MGET" | "SET" | "MSET" | "DEL" | "EXISTS"
+            | "INCR" | "DECR" | "INCRBY" | "DECRBY" | "EXPIRE" | "TTL" | "TYPE" | "KEYS" | "SCAN"
+            | "INFO" | "DBSIZE",
+            _,
+        ) => Reply::err(format!(
+            "wrong number of arguments for '{}' command",
+            name.to_lowercase()
+        )),
+        _ => Reply::err(format!("unknown command '{}'", name.to_lowercase())),
+    };
+    let duration = start.elapsed();
+    println!("RESP {} completed in {:.2?}", name, duration);
+    reply
+}
+
+fn authenticate(session: &mut Session, principal: Option<Arc<Principal>>) -> Reply {
+    match principal {
+        Some(principal) => {
+            println!("RESP client authenticated as '{}'", principal.name);
+            session.principal = Some(principal);
+            Reply::ok()
+        }
+        None => Reply::Error(
+            "WRONGPASS invalid username-password pair or user is disabled.".to_string(),
+        ),
+    }
+}
+
+// HELLO [protover [AUTH <user> <pass>] [SETNAME <name>]]
+async fn hello(session: &mut Session, acl: &Acl, args: &[&str]) -> Reply {
+    let protocol = match args.first() {
+        None => session.protocol,
+        Some(&"2") => 2,
+        Some(&"3") => 3,
+        Some(_) => return Reply::Error("NOPROTO unsupported protocol version".to_string()),
+    };
+    let mut options = args.iter().skip(1);
+    while let Some(option) = options.next() {
+        match (option.to_uppercase().as_str(), options.next()) {
+            ("AUTH", Some(user)) => {
+                let Some(password) = options.next() else {
+                    return Reply::err("syntax error");
+                };
+                if let Reply::Error(e) = authenticate(session, acl.login(user, password).await) {
+                    return Reply::Error(e);
+                }
+            }
+            ("SETNAME", Some(_)) => {}
+            _ => return Reply::err("syntax error"),
+        }
+    }
+    if session.principal.is_none() {
+        return Reply::Error(
+            "NOAUTH HELLO must be called with the client already authenticated, \
+            otherwise the HELLO <proto> AUTH <user> <pass> option can be used"
+                .to_string(),
+        );
+    }
+    session.protocol = protocol;
+    Reply::Map(vec![
+        (Reply::bulk("server"), Reply::bulk("humpback")),
+        (
+            Reply::bulk("version"),
+            Reply::bulk(env!("CARGO_PKG_VERSION")),
+        ),
+        (
+            Reply::bulk("proto"),
+            Reply::Integer(session.protocol as i64),
+        ),
+        (Reply::bulk("mode"), Reply::bulk("standalone")),
+        (Reply::bulk("role"), Reply::bulk("master")),
+        (Reply::bulk("modules"), Reply::Array(Vec::new())),
+    ])
+}
+
+fn info(session: &Session, dbs: &Databases) -> Reply {
+    let keys = dbs
+        .main()
+        .objects
+        .list(&ListFilter::default(), None, None)
+        .map(|page| page.items.len())
+        .unwrap_or(0);
+    let mut info = String::new();
+    info.push_str("# Server\r\n");
+    info.push_str(&format!(
+        "humpback_version:{}\r\n",
+        env!("CARGO_PKG_VERSION")
+    ));
+    info.push_str(&format!("redis_version:{}\r\n", env!("CARGO_PKG_VERSION")));
+    info.push_str(&format!("resp_protocol:{}\r\n", session.protocol));
+    info.push_str("\r\n# Keyspace\r\n");
+    info.push_str(&format!("db_main:keys={}\r\n", keys));
+    if session.core.name != "main" {
+        let keys = session
+            .core
+            .objects
+            .list(&ListFilter::default(), None, None)
+            .map(|page| page.items.len())
+            .unwrap_or(0);
+        info.push_str(&format!("db_{}:keys={}\r\n", session.core.name, keys));
+    }
+    Reply::Bulk(info.into_bytes())
+}
+
+async fn incr(core: &Arc<Core>, key: &str, delta: f64) -> Reply {
+    match core.incr_async(key, delta).await {
+        Ok(number) if number.fract() == 0.0 && number.abs() < i64::MAX as f64 => {
+            Reply::Integer(number as i64)
+        }
+        Ok(number) => Reply::bulk(number.to_string()),
+        Err(e) => Reply::err(e.to_string()),
+    }
+}
+
+// SCAN <cursor> [MATCH <glob>] [COUNT <n>], cursor 0 starts and ends the
+// iteration like in Redis
+async fn scan(core: &Core, user: &Principal, cursor: &str, args: &[&str]) -> Reply {
+    let mut options = ListOptions {
+        limit: Some(DEFAULT_SCAN_COUNT),
+        key_prefixes: user.key_prefixes().to_vec(),
+        ..Default::default()
+    };
+    if cursor != "0" {
+        match ListCursor::from_str(cursor) {
+            Ok(cursor) => options.cursor = Some(cursor),
+            Err(_) => return Reply::err("invalid cursor"),
+        }
+    }
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match (arg.to_uppercase().as_str(), args.next()) {
+            ("MATCH", Some(pattern)) => options.pattern = Some(KeyPattern::glob(pattern)),
+            ("COUNT", Some(n)) => match n.parse::<usize>() {
+                Ok(n) if n > 0 => options.limit = Some(n),
+                _ => return Reply::err("value is not an integer or out of range"),
+            },
+            _ => return Reply::err("syntax error"),
+        }
+    }
+    match core.list(options).await {
+        Ok(page) => {
+            let next = page
+                .cursor
+                .map(|cursor| cursor.to_string())
+                .unwrap_or_else(|| "0".to_string());
+            let keys = page
+                .items
+                .into_iter()
+                .map(|element| Reply::bulk(element.key))
+                .collect();
+            Reply::Array(vec![Reply::bulk(next), Reply::Array(keys)])
+        }
+        Err(_) => Reply::err("Unable to list objects"),
+    }
+}
+
+// The scope a command needs, on each listed key or on the database as a
+// whole when no key is listed. Listings only show accessible keys.
+fn required_access<'a>(name: &str, args: &[&'a str]) -> Option<(Scope, Vec<&'a str>)> {
+    let access = match (name, args) {
+        ("GET" | "TTL" | "TYPE", [key]) => (Scope::Get, vec![*key]),
+        ("MGET" | "EXISTS", keys) => (Scope::Get, keys.to_vec()),
+        ("KEYS" | "SCAN" | "DBSIZE", _) => (Scope::List, Vec::new()),
+        ("SET" | "INCR" | "DECR" | "INCRBY" | "DECRBY" | "EXPIRE", [key, ..]) => {
+            (Scope::Set, vec![*key])
+        }
+        ("MSET", pairs) => (Scope::Set, pairs.iter().step_by(2).copied().collect()),
+        ("DEL", keys) => (Scope::Delete, keys.to_vec()),
+        _ => return None,
+    };
+    Some(access)
+}
+
+// SET [EX <secs> | PX <ms>] [NX | XX]
+fn parse_set_options(args: &[&str]) -> Result<SetOptions, &'static str> {
+    let mut options = SetOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let condition = match arg.to_uppercase().as_str() {
+            "EX" | "PX" if options.ttl.is_some() => return Err("syntax error"),
+            "EX" => {
+                let secs = args.next().ok_or("syntax error")?;
+                let secs = secs
+                    .parse::<u64>()
+                    .map_err(|_| "invalid expire time in 'set' command")?;
+                options.ttl = Some(
+                    expiry::check_ttl(Duration::from_secs(secs))
+                        .map_err(|_| "invalid expire time in 'set' command")?,
+                );
+                continue;
+            }
+            "PX" => {
+                let ms = args.next().ok_or("syntax error")?;
+                let ms = ms
+                    .parse::<u64>()
+                    .map_err(|_| "invalid expire time in 'set' command")?;
+                options.ttl = Some(
+                    expiry::check_ttl(Duration::from_millis(ms))
+                        .map_err(|_| "invalid expire time in 'set' command")?,
+                );
+                continue;
+            }
+            "NX" => Condition::Absent,
+            "XX" => Condition::Present,
+            _ => return Err("syntax error"),
+        };
+        if options.condition.replace(condition).is_some() {
+            return Err("syntax error");
+        }
+    }
+    Ok(options)
+}
+
+// Redis has no value kinds, so numbers are sent as their text
+fn value_bytes(object: Object) -> Vec<u8> {
+    match object.desc.kind {
+        Kind::Number => {
+            let mut arr = [0u8; 8];
+            arr.copy_from_slice(&object.data[..8]);
+            f64::from_le_bytes(arr).to_string().into_bytes()
+        }
+        _ => object.data,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(input: &[u8], limits: &Limits) -> Result<Option<Vec<Vec<u8>>>, String> {
+        let mut reader = input;
+        read_command(&mut reader, limits).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn reads_arrays_and_inline_commands() {
+        let args = read(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n", &UNAUTHENTICATED).await;
+        assert_eq!(args, Ok(Some(vec![b"GET".to_vec(), b"k".to_vec()])));
+        let args = read(b"PING hello\r\n", &UNAUTHENTICATED).await;
+        assert_eq!(args, Ok(Some(vec![b"PING".to_vec(), b"hello".to_vec()])));
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_clients_send_small_commands() {
+        let many = format!("*{}\r\n", UNAUTHENTICATED.args + 1);
+        assert!(read(many.as_bytes(), &UNAUTHENTICATED).await.is_err());
+        assert!(read(many.as_bytes(), &AUTHENTICATED).await.is_ok());
+
+        let large = format!("*1\r\n${}\r\n", UNAUTHENTICATED.bulk_size + 1);
+        assert!(read(large.as_bytes(), &UNAUTHENTICATED).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn long_lines_are_refused() {
+        let line = vec![b'a'; MAX_LINE_SIZE + 1];
+        assert!(read(&line, &AUTHENTICATED).await.is_err());
+    }
+}
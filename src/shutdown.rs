@@ -0,0 +1,39 @@
+use std::sync::Arc;
+
+use tokio::{signal, sync::watch};
+
+/// Handle every server holds to learn when the process is shutting down.
+#[derive(Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    /// Starts listening for ctrl+c. Must be called inside a tokio runtime.
+    pub fn listen() -> Shutdown {
+        let (sender, receiver) = watch::channel(false);
+        let shutdown = Shutdown {
+            sender: Arc::new(sender),
+            receiver,
+        };
+        let signalled = shutdown.clone();
+        tokio::spawn(async move {
+            signal::ctrl_c().await.expect("Failed to listen for ctrl+c");
+            println!("Exit signal received\nInitiating graceful shutdown...");
+            signalled.trigger();
+        });
+        shutdown
+    }
+
+    /// Shuts every server down, e.g. when one of them failed.
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    /// Resolves once shutdown has started.
+    pub async fn recv(&mut self) {
+        // An error means the sender is gone, which only happens on exit
+        let _ = self.receiver.wait_for(|stopping| *stopping).await;
+    }
+}
@@ -1,87 +1,156 @@
 use crate::{
-    js::{self, core::Event, runtime::Runtime},
-    kv::{core::Core, objects::Kind},
-};
-use deno_core::serde_json::json;
-use std::{
-    error::Error,
-    io::Write,
-    str::FromStr,
-    sync::{Arc, Mutex},
-    time::Duration,
-};
-use std::{
-    sync::mpsc::{self, Receiver, Sender},
-    thread,
+    auth::{Acl, Principal, Scope},
+    config::Config,
+    js::{event::Event, runtime::Runtime},
+    kv::{
+        core::{Condition, Core, ListOptions, SetOptions},
+        databases::Databases,
+        expiry,
+        objects::{Kind, ListCursor, MAX_KEY_LEN, Object, ScanRange},
+        pattern::KeyPattern,
+        transaction::Transaction,
+    },
+    shutdown::Shutdown,
 };
+use std::{error::Error, str::FromStr, sync::Arc, time::Duration};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    io::{
+        AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter,
+    },
     net::{TcpListener, TcpStream},
-    signal,
-    sync::Notify,
+    task::JoinSet,
 };
 
-#[tokio::main]
-pub async fn run(core: Arc<Core>) -> Result<(), Box<dyn Error>> {
-    let notify_shutdown = Arc::new(Notify::new());
-
-    let listener = TcpListener::bind("127.0.0.1:8080").await?;
-    println!("Humpback KV Database is listening on 127.0.0.1:8080");
+pub async fn run(
+    dbs: Arc<Databases>,
+    runtime: Arc<Runtime>,
+    acl: Arc<Acl>,
+    config: &Config,
+    mut shutdown: Shutdown,
+) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(&config.tcp.listen).await?;
+    println!("Humpback KV Database is listening on {}", config.tcp.listen);
 
-    let shutdown_notify = Arc::clone(&notify_shutdown);
-    tokio::spawn(async move {
-        signal::ctrl_c().await.expect("Failed to listen ctrl+c");
-        shutdown_notify.notify_waiters();
-    });
-    let runtime: Arc<Runtime> = js::runtime::Runtime::new(Arc::clone(&core));
+    let mut connections = JoinSet::new();
     loop {
         tokio::select! {
             Ok((socket, addr)) = listener.accept() => {
                 println!("New connection from: {}", addr);
-                let core = Arc::clone(&core);
+                let dbs = Arc::clone(&dbs);
                 let runtime = Arc::clone(&runtime);
-                tokio::spawn(async move {
-                    if let Err(e) = handle_client(socket, core,runtime).await {
+                let acl = Arc::clone(&acl);
+                let shutdown = shutdown.clone();
+                connections.spawn(async move {
+                    if let Err(e) = handle_client(socket, dbs, runtime, acl, shutdown).await {
                         eprintln!("Connection error: {}", e);
                     }
                 });
             }
-            _ = notify_shutdown.notified() => {
+            // Reap finished connections so the set doesn't grow forever
+            Some(_) = connections.join_next() => {}
+            _ = shutdown.recv() => {
                 break;
             }
         }
     }
-    println!("Exit signal received\nInitiating graceful shutdown...");
-    tokio::time::sleep(Duration::from_millis(500)).await;
-    let exit_core = Arc::clone(&core);
-
-    let mut desc_file = exit_core.desc_file.lock().unwrap();
-    desc_file.flush()?;
-
-    let mut data_file = exit_core.data_file.lock().unwrap();
-    data_file.flush()?;
+    // Clients finish the command they are running, then get disconnected
+    while connections.join_next().await.is_some() {}
 
-    println!("All data flushed to disk");
-    println!("Resources released");
+    println!("TCP server stopped");
     Ok(())
 }
 
 async fn handle_client(
     socket: TcpStream,
-    core: Arc<Core>,
+    dbs: Arc<Databases>,
     runtime: Arc<Runtime>,
-) -> Result<(), Box<dyn Error>> {
-    let (reader, mut writer) = socket.into_split();
+    acl: Arc<Acl>,
+    mut shutdown: Shutdown,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (reader, writer) = socket.into_split();
     let mut buf_reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
     let mut line = String::new();
+    // Commands queued between MULTI and EXEC
+    let mut txn: Option<Transaction> = None;
+    // Database picked with SELECT, `main` until then
+    let mut core: Arc<Core> = dbs.main();
+    // Set by AUTH, every other command is refused until then
+    let mut principal: Option<Arc<Principal>> = None;
     loop {
+        // Clients may pipeline many commands without waiting for replies.
+        // They run in order and their replies go out in one write once
+        // everything already received has been handled.
+        if buf_reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
         line.clear();
-        let byte_read = buf_reader.read_line(&mut line).await?;
+        let byte_read = tokio::select! {
+            read = buf_reader.read_line(&mut line) => read?,
+            _ = shutdown.recv() => break,
+        };
         if byte_read == 0 {
             break;
         }
         let trimmed = line.trim();
-        let parts: Vec<&str> = trimmed.splitn(3, ' ').collect();
+        let parts: Vec<&str> = trimmed.split_whitespace().collect();
+
+        if let ["AUTH", credentials @ ..] = parts.as_slice() {
+            let authenticated = match credentials {
+                [token] => acl.authenticate_token(token).await,
+                [user, password] => acl.login(user, password).await,
+                _ => {
+                    writer
+                        .write_all(b"> ERR Use AUTH <user> <password> or AUTH <token>\n")
+                        .await?;
+                    continue;
+                }
+            };
+            match authenticated {
+                Some(authenticated) => {
+                    println!("Authenticated as '{}'", authenticated.name);
+                    principal = Some(authenticated);
+                    writer.write_all(b"> OK\n").await?;
+                }
+                None => {
+                    writer.write_all(b"> ERR Invalid credentials\n").await?;
+                }
+            }
+            continue;
+        }
+        // Values of refused writes are still on the wire and get skipped
+        let Some(user) = principal.clone() else {
+            for len in value_lens(&parts) {
+                skip_value(&mut buf_reader, len).await?;
+            }
+            writer
+                .write_all(b"> ERR NOAUTH Authentication required\n")
+                .await?;
+            continue;
+        };
+        if let Some((scope, keys)) = required_access(&parts)
+            && let Err(e) = check_access(&user, scope, &keys)
+        {
+            for len in value_lens(&parts) {
+                skip_value(&mut buf_reader, len).await?;
+            }
+            writer
+                .write_all(format!("> ERR {}\n", e).as_bytes())
+                .await?;
+            continue;
+        }
+        if txn.is_some() && !allowed_in_multi(&parts) {
+            writer
+                .write_all(
+                    format!(
+                        "> ERR {} can not be used inside MULTI, only SET, MSET, DELETE and MDEL are queued\n",
+                        parts.first().unwrap_or(&"")
+                    )
+                    .as_bytes(),
+                )
+                .await?;
+            continue;
+        }
 
         match parts.as_slice() {
             ["GET", key] => {
@@ -89,29 +158,33 @@ async fn handle_client(
                 let object = core.get_async(key).await;
                 let duration = start.elapsed();
                 println!("GET completed in {:.2?}", duration);
-                match object {
-                    Some(object) => {
-                        writer.write_all(b"> SUCCESS\n").await?;
-                        match object.desc.kind {
-                            Kind::Number => {
-                                let mut arr = [0u8; 8];
-                                arr.copy_from_slice(&object.data[..8]);
-                                let number = f64::from_le_bytes(arr);
-                                writer.write_all(format!("{}\n", number).as_bytes()).await?;
-                            }
-                            _ => {
-                                writer.write_all(&object.data).await?;
-                            }
-                        }
-                    }
-                    None => {
-                        writer.write_all(b"> NOT FOUND\n").await?;
-                    }
+                write_object(&mut writer, object).await?;
+            }
+            ["MGET", keys @ ..] if !keys.is_empty() => {
+                let start = std::time::Instant::now();
+                let keys = keys.iter().map(|key| key.to_string()).collect();
+                let objects = core.get_many_async(keys).await;
+                let duration = start.elapsed();
+                println!(
+                    "MGET completed in {:.2?} ({} keys)",
+                    duration,
+                    objects.len()
+                );
+                writer
+                    .write_all(format!("> {} values\n", objects.len()).as_bytes())
+                    .await?;
+                for object in objects {
+                    write_object(&mut writer, object).await?;
                 }
             }
             ["DELETE", key] => {
+                if let Some(txn) = txn.as_mut() {
+                    txn.delete(key);
+                    writer.write_all(b"> QUEUED\n").await?;
+                    continue;
+                }
                 let start = std::time::Instant::now();
-                match core.delete_soft(key).await {
+                match core.delete_soft_async(key).await {
                     Ok(_) => {
                         writer.write_all(b"> SUCCESS\n").await?;
                     }
@@ -122,64 +195,306 @@ async fn handle_client(
                 let duration = start.elapsed();
                 println!("DELETE completed in {:.2?}", duration);
             }
-            ["SET", key, kind] => {
-                if key.len() > 256 {
+            ["SET", key, kind, len, args @ ..] => {
+                let Ok(len) = len.parse::<usize>() else {
                     writer
-                        .write_all(b"> ERR Key is too long. Max key length - 256 bytes\n")
+                        .write_all(b"> ERR Use SET <key> <type> <len>, then <len> bytes and CRLF\n")
                         .await?;
                     continue;
-                }
-                let kind = Kind::from_str(kind).unwrap();
-                writer.write_all(b"> WRITE DATA\n").await?;
-                let kind = match Kind::from_str(&kind.to_string()) {
-                    Ok(k) => k,
-                    Err(_) => {
-                        writer.write_all(b"> ERR Unknown kind\n").await?;
+                };
+                // The value is on the wire anyway, so skip it on errors to
+                // stay in sync with the client
+                let (kind, options) = match parse_set(key, kind, len, args) {
+                    Ok(parsed) => parsed,
+                    Err(e) => {
+                        skip_value(&mut buf_reader, len).await?;
+                        writer
+                            .write_all(format!("> ERR {}\n", e).as_bytes())
+                            .await?;
                         continue;
                     }
                 };
-                let buf_size = match kind {
-                    Kind::Number => 16,
-                    Kind::Boolean => 4,
-                    Kind::String => 1024 * 16,      // 16 KB
-                    Kind::Json => 1024 * 64,        // 64 KB
-                    Kind::Blob => 1024 * 1024 * 20, // 20 Mb
-                    Kind::Object => 1024 * 64,
-                    Kind::Js => 1024 * 64,
+                let Some(data_buf) = read_value(&mut buf_reader, len).await? else {
+                    writer
+                        .write_all(b"> ERR Value must be followed by CRLF\n")
+                        .await?;
+                    break;
                 };
-                let mut data_buf = vec![0; buf_size];
-                let data_size = buf_reader.read(&mut data_buf).await?;
-                data_buf.truncate(data_size);
+                let data_size = data_buf.len();
+                if let Some(txn) = txn.as_mut() {
+                    txn.set(key, kind, data_buf, options);
+                    writer.write_all(b"> QUEUED\n").await?;
+                    continue;
+                }
                 let start = std::time::Instant::now();
-                core.set_async(key, kind, data_buf).await;
+                let version = core.set_async(key, kind, data_buf, options).await;
                 let duration = start.elapsed();
                 println!("SET completed in {:.2?} ({} bytes)", duration, data_size);
-                writer.write_all(b"> SUCCESS\n").await?;
+                match version {
+                    Ok(Some(version)) => {
+                        writer
+                            .write_all(format!("> SUCCESS {}\n", version).as_bytes())
+                            .await?;
+                    }
+                    Ok(None) => {
+                        writer.write_all(b"> CONFLICT\n").await?;
+                    }
+                    Err(e) => {
+                        writer
+                            .write_all(format!("> ERR {}\n", e).as_bytes())
+                            .await?;
+                    }
+                }
+            }
+            ["MSET", args @ ..] => {
+                let lens: Option<Vec<usize>> = args
+                    .chunks(3)
+                    .map(|entry| entry.get(2).and_then(|len| len.parse().ok()))
+                    .collect();
+                let Some(lens) = lens.filter(|lens| !lens.is_empty()) else {
+                    writer
+                        .write_all(
+                            b"> ERR Use MSET <key> <type> <len> [<key> <type> <len> ...], \
+                            then every value as <len> bytes and CRLF\n",
+                        )
+                        .await?;
+                    continue;
+                };
+                let mut entries = Vec::with_capacity(lens.len());
+                let mut invalid = None;
+                for (entry, len) in args.chunks(3).zip(&lens) {
+                    match parse_set(entry[0], entry[1], *len, &[]) {
+                        Ok((kind, _)) => entries.push((entry[0].to_string(), kind)),
+                        Err(e) => {
+                            invalid = Some(format!("{}: {}", entry[0], e));
+                            break;
+                        }
+                    }
+                }
+                if let Some(e) = invalid {
+                    for len in lens {
+                        skip_value(&mut buf_reader, len).await?;
+                    }
+                    writer
+                        .write_all(format!("> ERR {}\n", e).as_bytes())
+                        .await?;
+                    continue;
+                }
+                let mut values = Vec::with_capacity(entries.len());
+                for len in lens {
+                    match read_value(&mut buf_reader, len).await? {
+                        Some(value) => values.push(value),
+                        None => break,
+                    }
+                }
+                if values.len() < entries.len() {
+                    writer
+                        .write_all(b"> ERR Value must be followed by CRLF\n")
+                        .await?;
+                    break;
+                }
+                let entries: Vec<_> = entries
+                    .into_iter()
+                    .zip(values)
+                    .map(|((key, kind), value)| (key, kind, value))
+                    .collect();
+                if let Some(txn) = txn.as_mut() {
+                    for (key, kind, value) in entries {
+                        txn.set(&key, kind, value, SetOptions::default());
+                    }
+                    writer.write_all(b"> QUEUED\n").await?;
+                    continue;
+                }
+                let start = std::time::Instant::now();
+                let count = entries.len();
+                match core.set_many_async(entries).await {
+                    Ok(versions) => {
+                        let versions = versions
+                            .iter()
+                            .map(|version| version.to_string())
+                            .collect::<Vec<_>>()
+                            .join(" ");
+                        writer
+                            .write_all(format!("> SUCCESS {}\n", versions).as_bytes())
+                            .await?;
+                    }
+                    Err(e) => {
+                        writer
+                            .write_all(format!("> ERR {}\n", e).as_bytes())
+                            .await?;
+                    }
+                }
+                let duration = start.elapsed();
+                println!("MSET completed in {:.2?} ({} keys)", duration, count);
+            }
+            ["MDEL", keys @ ..] if !keys.is_empty() => {
+                if let Some(txn) = txn.as_mut() {
+                    for key in keys {
+                        txn.delete(key);
+                    }
+                    writer.write_all(b"> QUEUED\n").await?;
+                    continue;
+                }
+                let start = std::time::Instant::now();
+                let keys = keys.iter().map(|key| key.to_string()).collect();
+                match core.delete_many_async(keys).await {
+                    Ok(deleted) => {
+                        writer
+                            .write_all(format!("> SUCCESS {}\n", deleted).as_bytes())
+                            .await?;
+                    }
+                    Err(e) => {
+                        writer
+                            .write_all(format!("> ERR {}\n", e).as_bytes())
+                            .await?;
+                    }
+                }
+                let duration = start.elapsed();
+                println!("MDEL completed in {:.2?}", duration);
             }
-            ["LIST"] => {
+            ["INCR", key] | ["DECR", key] | ["INCRBY", key, _] => {
+                let delta = match parts.as_slice() {
+                    ["INCRBY", _, delta] => match delta.parse::<f64>() {
+                        Ok(delta) => delta,
+                        Err(_) => {
+                            writer.write_all(b"> ERR Invalid increment\n").await?;
+                            continue;
+                        }
+                    },
+                    ["DECR", _] => -1.0,
+                    _ => 1.0,
+                };
+                let start = std::time::Instant::now();
+                match core.incr_async(key, delta).await {
+                    Ok(number) => {
+                        writer
+                            .write_all(format!("> {}\n", number).as_bytes())
+                            .await?;
+                    }
+                    Err(e) => {
+                        writer
+                            .write_all(format!("> ERR {}\n", e).as_bytes())
+                            .await?;
+                    }
+                }
+                let duration = start.elapsed();
+                println!("INCR completed in {:.2?}", duration);
+            }
+            ["SELECT", db] => {
+                if txn.is_some() {
+                    writer.write_all(b"> ERR SELECT inside MULTI\n").await?;
+                    continue;
+                }
+                match dbs.get(Some(db)) {
+                    Ok(selected) => {
+                        core = selected;
+                        writer.write_all(b"> OK\n").await?;
+                    }
+                    Err(e) => {
+                        writer
+                            .write_all(format!("> ERR {}\n", e).as_bytes())
+                            .await?;
+                    }
+                }
+            }
+            ["MULTI"] => {
+                if txn.is_some() {
+                    writer
+                        .write_all(b"> ERR MULTI calls can not be nested\n")
+                        .await?;
+                    continue;
+                }
+                txn = Some(Transaction::new());
+                writer.write_all(b"> OK\n").await?;
+            }
+            ["EXEC"] => {
+                let Some(queued) = txn.take() else {
+                    writer.write_all(b"> ERR EXEC without MULTI\n").await?;
+                    continue;
+                };
+                let start = std::time::Instant::now();
+                let ops = queued.len();
+                match core.commit_async(queued).await {
+                    Ok(_) => {
+                        writer.write_all(b"> SUCCESS\n").await?;
+                    }
+                    Err(e) => {
+                        writer
+                            .write_all(format!("> ERR Transaction failed: {}\n", e).as_bytes())
+                            .await?;
+                    }
+                }
+                let duration = start.elapsed();
+                println!("EXEC completed in {:.2?} ({} ops)", duration, ops);
+            }
+            ["DISCARD"] => {
+                if txn.take().is_none() {
+                    writer.write_all(b"> ERR DISCARD without MULTI\n").await?;
+                    continue;
+                }
+                writer.write_all(b"> OK\n").await?;
+            }
+            ["TTL", key] => match core.ttl(key) {
+                Some(Some(ttl)) => {
+                    writer
+                        .write_all(format!("> {}\n", ttl.as_secs()).as_bytes())
+                        .await?;
+                }
+                Some(None) => {
+                    writer.write_all(b"> -1\n").await?;
+                }
+                None => {
+                    writer.write_all(b"> NOT FOUND\n").await?;
+                }
+            },
+            ["PERSIST", key] => match core.set_expiry_async(key, None).await {
+                Ok(_) => {
+                    writer.write_all(b"> SUCCESS\n").await?;
+                }
+                Err(_) => {
+                    writer.write_all(b"> NOT FOUND\n").await?;
+                }
+            },
+            ["LIST", args @ ..] => {
+                let mut options = match parse_list_options(args) {
+                    Ok(options) => options,
+                    Err(e) => {
+                        writer
+                            .write_all(format!("> ERR {}\n", e).as_bytes())
+                            .await?;
+                        continue;
+                    }
+                };
+                options.key_prefixes = user.key_prefixes().to_vec();
                 let start = std::time::Instant::now();
 
-                match core.list().await {
-                    Ok(list) => {
-                        if list.len() <= 0 {
+                match core.list(options).await {
+                    Ok(page) => {
+                        let list = page.items;
+                        if list.is_empty() {
                             writer.write_all(b"> No objects\n").await?;
-                            continue;
                         }
                         for chunk in list.chunks(2) {
                             let line = chunk
                                 .iter()
                                 .map(|element| {
                                     format!(
-                                        "[{}] <{}> size: {}",
+                                        "[{}] <{}> size: {} version: {}",
                                         element.key,
                                         element.kind.to_string().to_uppercase(),
-                                        element.size
+                                        element.size,
+                                        element.version
                                     )
                                 })
                                 .collect::<Vec<_>>()
                                 .join(" | ");
                             writer.write_all(format!("{}\n", line).as_bytes()).await?;
                         }
+                        if let Some(cursor) = page.cursor {
+                            writer
+                                .write_all(format!("> CURSOR {}\n", cursor).as_bytes())
+                                .await?;
+                        }
                     }
                     Err(_) => {
                         writer.write_all(b"> ERR Unable to list objects\n").await?;
@@ -188,9 +503,18 @@ async fn handle_client(
                 let duration = start.elapsed();
                 println!("LIST completed in {:.2?}", duration);
             }
-            ["LIST_TYPE", kind] => {
+            ["LIST_TYPE", kind, args @ ..] => {
+                let mut options = match parse_list_options(args) {
+                    Ok(options) => options,
+                    Err(e) => {
+                        writer
+                            .write_all(format!("> ERR {}\n", e).as_bytes())
+                            .await?;
+                        continue;
+                    }
+                };
                 let start = std::time::Instant::now();
-                let kind_enum = match Kind::from_str(&kind) {
+                let kind_enum = match Kind::from_str(kind) {
                     Ok(k) => k,
                     Err(_) => {
                         writer.write_all(b"> ERR Invalid type\n").await?;
@@ -198,20 +522,31 @@ async fn handle_client(
                     }
                 };
 
-                match core.list_by_kind(kind_enum).await {
-                    Ok(list) => {
-                        if list.len() <= 0 {
+                options.key_prefixes = user.key_prefixes().to_vec();
+                match core.list_by_kind(kind_enum, options).await {
+                    Ok(page) => {
+                        let list = page.items;
+                        if list.is_empty() {
                             writer.write_all(b"> No objects\n").await?;
-                            continue;
                         }
                         for chunk in list.chunks(2) {
                             let line = chunk
                                 .iter()
-                                .map(|element| format!("[{}] size: {}", element.key, element.size))
+                                .map(|element| {
+                                    format!(
+                                        "[{}] size: {} version: {}",
+                                        element.key, element.size, element.version
+                                    )
+                                })
                                 .collect::<Vec<_>>()
                                 .join(" | ");
                             writer.write_all(format!("{}\n", line).as_bytes()).await?;
                         }
+                        if let Some(cursor) = page.cursor {
+                            writer
+                                .write_all(format!("> CURSOR {}\n", cursor).as_bytes())
+                                .await?;
+                        }
                     }
                     Err(_) => {
                         writer.write_all(b"> ERR Unable to list objects\n").await?;
@@ -220,24 +555,80 @@ async fn handle_client(
                 let duration = start.elapsed();
                 println!("LIST completed in {:.2?}", duration);
             }
+            ["KEYS", pattern] => {
+                let options = ListOptions {
+                    pattern: Some(KeyPattern::glob(pattern)),
+                    key_prefixes: user.key_prefixes().to_vec(),
+                    ..Default::default()
+                };
+                match core.list(options).await {
+                    Ok(page) => {
+                        let keys = page.items;
+                        writer
+                            .write_all(format!("> {} keys\n", keys.len()).as_bytes())
+                            .await?;
+                        for element in keys {
+                            writer
+                                .write_all(format!("{}\n", element.key).as_bytes())
+                                .await?;
+                        }
+                    }
+                    Err(_) => {
+                        writer.write_all(b"> ERR Unable to list objects\n").await?;
+                    }
+                }
+            }
+            ["SCAN", ..] | ["RANGE", ..] => {
+                let (range, limit) = match parse_scan(&parts) {
+                    Ok(scan) => scan,
+                    Err(e) => {
+                        writer
+                            .write_all(format!("> ERR {}\n", e).as_bytes())
+                            .await?;
+                        continue;
+                    }
+                };
+                let start = std::time::Instant::now();
+                match core.scan(range, limit, user.key_prefixes()) {
+                    Ok(list) => {
+                        if list.is_empty() {
+                            writer.write_all(b"> No objects\n").await?;
+                            continue;
+                        }
+                        for element in list {
+                            writer
+                                .write_all(
+                                    format!(
+                                        "[{}] <{}> size: {} version: {}\n",
+                                        element.key,
+                                        element.kind.to_string().to_uppercase(),
+                                        element.size,
+                                        element.version
+                                    )
+                                    .as_bytes(),
+                                )
+                                .await?;
+                        }
+                    }
+                    Err(_) => {
+                        writer.write_all(b"> ERR Unable to scan objects\n").await?;
+                    }
+                }
+                let duration = start.elapsed();
+                println!("SCAN completed in {:.2?}", duration);
+            }
             ["EXEC", key] => {
                 let object = core.get_async(key).await;
                 match object {
-                    Some(object) => {
-                        let _ = match String::from_utf8(object.data) {
-                            Ok(s) => runtime.execute(Event {
-                                id: 1,
-                                path: "/something".into(),
-                                payload: json!({"some": "data"}),
-                                headers: None,
-                                event_type: "request".into(),
-                            }),
-                            Err(_) => {
-                                writer.write_all(b"> INVALID UTF-8\n").await?;
-                                continue;
-                            }
-                        };
-                    }
+                    Some(object) => match String::from_utf8(object.data) {
+                        Ok(code) => {
+                            runtime.push_event(Event::new_code_event(code));
+                            writer.write_all(b"> SUCCESS\n").await?;
+                        }
+                        Err(_) => {
+                            writer.write_all(b"> INVALID UTF-8\n").await?;
+                        }
+                    },
                     None => {
                         writer.write_all(b"> NOT FOUND\n").await?;
                     }
@@ -247,11 +638,250 @@ async fn handle_client(
                 writer
                     .write_all(
                         b"> ERR Invalid command. Use one of: \
-                    GET <key> | SET <key> <type> | LIST | LIST_TYPE <type>\n",
+                    AUTH <user> <password> | AUTH <token> | SELECT <db> | GET <key> | SET <key> <type> <len> [EX <secs>] [IFVERSION <n> | NX | XX] | \
+                    MGET <key>... | MSET <key> <type> <len> [<key> <type> <len> ...] | MDEL <key>... | \
+                    INCR <key> | DECR <key> | INCRBY <key> <delta> | TTL <key> | PERSIST <key> | \
+                    MULTI | EXEC | DISCARD | KEYS <glob> | \
+                    LIST [MATCH <glob> | REGEX <re>] [CURSOR <c>] [LIMIT <n>] | \
+                    LIST_TYPE <type> [MATCH <glob> | REGEX <re>] [CURSOR <c>] [LIMIT <n>] | \
+                    SCAN <prefix> [LIMIT <n>] | RANGE <start> <end> [LIMIT <n>]\n",
                     )
                     .await?;
             }
         }
     }
+    writer.flush().await?;
     Ok(())
 }
+
+// What a command needs from the user: `scope` on each listed key, or on
+// the database as a whole when no key is listed. Listings are filtered
+// down to the keys the user may access instead.
+fn required_access<'a>(parts: &[&'a str]) -> Option<(Scope, Vec<&'a str>)> {
+    let access = match parts {
+        ["GET", key] | ["TTL", key] => (Scope::Get, vec![*key]),
+        ["MGET", keys @ ..] => (Scope::Get, keys.to_vec()),
+        ["LIST", ..] | ["LIST_TYPE", ..] | ["KEYS", ..] | ["SCAN", ..] | ["RANGE", ..] => {
+            (Scope::List, Vec::new())
+        }
+        ["SET", key, ..]
+        | ["INCR", key]
+        | ["DECR", key]
+        | ["INCRBY", key, _]
+        | ["PERSIST", key] => (Scope::Set, vec![*key]),
+        ["MSET", args @ ..] => (Scope::Set, args.iter().step_by(3).copied().collect()),
+        ["DELETE", key] => (Scope::Delete, vec![*key]),
+        ["MDEL", keys @ ..] => (Scope::Delete, keys.to_vec()),
+        // EXEC without a key commits MULTI, its commands were checked when queued
+        ["EXEC", key] => (Scope::Exec, vec![*key]),
+        _ => return None,
+    };
+    Some(access)
+}
+
+// Only writes can be queued, anything else would run outside the transaction
+fn allowed_in_multi(parts: &[&str]) -> bool {
+    matches!(
+        parts,
+        [] | ["SET", ..]
+            | ["MSET", ..]
+            | ["DELETE", _]
+            | ["MDEL", ..]
+            | ["MULTI"]
+            | ["EXEC"]
+            | ["DISCARD"]
+    )
+}
+
+fn check_access(user: &Principal, scope: Scope, keys: &[&str]) -> Result<(), String> {
+    if keys.is_empty() {
+        return user.check(scope, None);
+    }
+    keys.iter().try_for_each(|key| user.check(scope, Some(key)))
+}
+
+// Lengths of the values that follow a SET or MSET line
+fn value_lens(parts: &[&str]) -> Vec<usize> {
+    match parts {
+        ["SET", _, _, len, ..] => len.parse().into_iter().collect(),
+        ["MSET", args @ ..] => args
+            .chunks(3)
+            .map(|entry| entry.get(2).and_then(|len| len.parse().ok()))
+            .collect::<Option<Vec<_>>>()
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+// Values are sent as `<len>\r\n<bytes>\r\n`, so blobs may contain anything
+async fn read_value<R>(reader: &mut R, len: usize) -> std::io::Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut data = vec![0; len + 2];
+    reader.read_exact(&mut data).await?;
+    if !data.ends_with(b"\r\n") {
+        return Ok(None);
+    }
+    data.truncate(len);
+    Ok(Some(data))
+}
+
+async fn skip_value<R>(reader: &mut R, len: usize) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut value = reader.take(len as u64 + 2);
+    tokio::io::copy(&mut value, &mut tokio::io::sink()).await?;
+    Ok(())
+}
+
+// `> SUCCESS <version> <kind> <len>` and the value, or `> NOT FOUND`
+async fn write_object<W>(writer: &mut W, object: Option<Object>) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let Some(object) = object else {
+        return writer.write_all(b"> NOT FOUND\n").await;
+    };
+    let payload = match object.desc.kind {
+        Kind::Number => {
+            let mut arr = [0u8; 8];
+            arr.copy_from_slice(&object.data[..8]);
+            let number = f64::from_le_bytes(arr);
+            number.to_string().into_bytes()
+        }
+        _ => object.data,
+    };
+    let header = format!(
+        "> SUCCESS {} {} {}",
+        object.desc.version,
+        object.desc.kind,
+        payload.len()
+    );
+    write_value(writer, &header, &payload).await
+}
+
+async fn write_value<W>(writer: &mut W, header: &str, data: &[u8]) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(header.as_bytes()).await?;
+    writer.write_all(b"\r\n").await?;
+    writer.write_all(data).await?;
+    writer.write_all(b"\r\n").await
+}
+
+fn max_value_size(kind: &Kind) -> usize {
+    match kind {
+        Kind::Number => 16,
+        Kind::Boolean => 4,
+        Kind::String => 1024 * 16,      // 16 KB
+        Kind::Json => 1024 * 64,        // 64 KB
+        Kind::Blob => 1024 * 1024 * 20, // 20 Mb
+        Kind::Object => 1024 * 64,
+        Kind::Js => 1024 * 64,
+    }
+}
+
+// Checks everything about a SET before its value is read
+fn parse_set(
+    key: &str,
+    kind: &str,
+    len: usize,
+    args: &[&str],
+) -> Result<(Kind, SetOptions), String> {
+    if key.len() > MAX_KEY_LEN {
+        return Err(format!(
+            "Key is too long. Max key length - {} bytes",
+            MAX_KEY_LEN
+        ));
+    }
+    let kind = Kind::from_str(kind).map_err(|_| "Unknown kind".to_string())?;
+    if len > max_value_size(&kind) {
+        return Err(format!(
+            "Value is too large. Max {} size - {} bytes",
+            kind,
+            max_value_size(&kind)
+        ));
+    }
+    let options = parse_set_options(args)?;
+    Ok((kind, options))
+}
+
+// Parses the optional `[EX <secs>] [IFVERSION <n> | NX | XX]` tail of SET
+fn parse_set_options(args: &[&str]) -> Result<SetOptions, &'static str> {
+    let mut options = SetOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let condition = match *arg {
+            "EX" => {
+                let secs = args.next().ok_or("Missing expire time")?;
+                let secs = secs.parse::<u64>().map_err(|_| "Invalid expire time")?;
+                options.ttl = Some(expiry::check_ttl(Duration::from_secs(secs))?);
+                continue;
+            }
+            "IFVERSION" => {
+                let version = args.next().ok_or("Missing version")?;
+                Condition::Version(version.parse().map_err(|_| "Invalid version")?)
+            }
+            "NX" => Condition::Absent,
+            "XX" => Condition::Present,
+            _ => return Err("Invalid SET option"),
+        };
+        if options.condition.replace(condition).is_some() {
+            return Err("Use only one of IFVERSION, NX and XX");
+        }
+    }
+    Ok(options)
+}
+
+// SCAN <prefix> [LIMIT <n>] | RANGE <start> <end> [LIMIT <n>]
+fn parse_scan(parts: &[&str]) -> Result<(ScanRange, Option<usize>), &'static str> {
+    let (range, rest) = match parts {
+        ["SCAN", prefix, rest @ ..] => (ScanRange::Prefix(prefix.to_string()), rest),
+        ["RANGE", start, end, rest @ ..] => (
+            ScanRange::Range {
+                start: start.to_string(),
+                end: Some(end.to_string()),
+            },
+            rest,
+        ),
+        _ => return Err("Use SCAN <prefix> [LIMIT <n>] or RANGE <start> <end> [LIMIT <n>]"),
+    };
+    let limit = match rest {
+        [] => None,
+        ["LIMIT", n] => Some(n.parse().map_err(|_| "Invalid limit")?),
+        _ => return Err("Invalid SCAN option"),
+    };
+    Ok((range, limit))
+}
+
+// Optional `[MATCH <glob> | REGEX <re>] [CURSOR <c>] [LIMIT <n>]` tail of
+// LIST and LIST_TYPE
+fn parse_list_options(args: &[&str]) -> Result<ListOptions, String> {
+    let mut options = ListOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "MATCH" => {
+                let pattern = args.next().ok_or("Missing pattern")?;
+                options.pattern = Some(KeyPattern::glob(pattern));
+            }
+            "REGEX" => {
+                let pattern = args.next().ok_or("Missing pattern")?;
+                options.pattern = Some(KeyPattern::regex(pattern)?);
+            }
+            "CURSOR" => {
+                let token = args.next().ok_or("Missing cursor")?;
+                options.cursor = Some(ListCursor::from_str(token)?);
+            }
+            "LIMIT" => {
+                let n = args.next().ok_or("Missing limit")?;
+                options.limit = Some(n.parse().map_err(|_| "Invalid limit")?);
+            }
+            _ => return Err("Invalid LIST option".to_string()),
+        }
+    }
+    Ok(options)
+}
@@ -1,48 +1,82 @@
 import asyncio
+import os
 import random
 import string
 import time
 
 HOST = '127.0.0.1'
-PORT = 8080
+PORT = 8081
+TOKEN = os.environ['HUMPBACK_TOKEN']
 NUM_REQUESTS = 10000
+NUM_CONNECTIONS = 16
+# Commands sent before reading their replies
+PIPELINE_DEPTH = 64
+MSET_BATCH = 100
 
 def random_string(length=16):
     return ''.join(random.choices(string.ascii_letters + string.digits, k=length))
 
-async def send_set(index):
-    reader, writer = await asyncio.open_connection(HOST, PORT)
-    key = f"key{index}"
-    kind = "string"
-    value = f"value{index}_{random_string(32)}"
-    message = f"SET {key} {kind}\n"
+def set_message(key, value):
+    return f"SET {key} string {len(value)}\r\n".encode() + value + b"\r\n"
+
+def mset_message(entries):
+    header = " ".join(f"{key} string {len(value)}" for key, value in entries)
+    values = b"".join(value + b"\r\n" for _, value in entries)
+    return f"MSET {header}\r\n".encode() + values
 
-    writer.write(message.encode())
+async def connect():
+    reader, writer = await asyncio.open_connection(HOST, PORT)
+    writer.write(f"AUTH {TOKEN}\r\n".encode())
     await writer.drain()
+    response = await reader.readline()
+    if not response.decode().startswith("> OK"):
+        raise RuntimeError(f"AUTH failed: {response.decode().strip()}")
+    return reader, writer
 
-    ready = await reader.readline()
-    if ready.decode().strip() != "READY":
-        print(f"[{index}] Unexpected response: {ready}")
-        writer.close()
-        await writer.wait_closed()
-        return
+async def pipeline_sets(indexes):
+    reader, writer = await connect()
+    for start in range(0, len(indexes), PIPELINE_DEPTH):
+        batch = indexes[start:start + PIPELINE_DEPTH]
+        for index in batch:
+            value = f"value{index}_{random_string(32)}".encode()
+            writer.write(set_message(f"key{index}", value))
+        await writer.drain()
 
-    writer.write(value.encode())
-    await writer.drain()
+        # Replies come back in the order the commands were sent
+        for index in batch:
+            response = await reader.readline()
+            if not response.decode().startswith("> SUCCESS"):
+                print(f"[{index}] Set failed: {response.decode().strip()}")
 
-    response = await reader.readline()
-    if response.decode().strip() != "SUCCESS":
-        print(f"[{index}] Set failed: {response.decode().strip()}")
+    writer.close()
+    await writer.wait_closed()
+
+async def batch_sets(indexes):
+    reader, writer = await connect()
+    for start in range(0, len(indexes), MSET_BATCH):
+        batch = indexes[start:start + MSET_BATCH]
+        entries = [(f"mkey{i}", f"value{i}_{random_string(32)}".encode()) for i in batch]
+        writer.write(mset_message(entries))
+        await writer.drain()
+
+        response = await reader.readline()
+        if not response.decode().startswith("> SUCCESS"):
+            print(f"[{batch[0]}..] MSET failed: {response.decode().strip()}")
 
     writer.close()
     await writer.wait_closed()
 
-async def main():
+async def run(name, worker):
+    indexes = list(range(NUM_REQUESTS))
+    shards = [indexes[i::NUM_CONNECTIONS] for i in range(NUM_CONNECTIONS)]
     start = time.perf_counter()
-    tasks = [send_set(i) for i in range(NUM_REQUESTS)]
-    await asyncio.gather(*tasks)
+    await asyncio.gather(*(worker(shard) for shard in shards))
     duration = time.perf_counter() - start
-    print(f"Sent {NUM_REQUESTS} SET requests in {duration:.2f} seconds")
+    print(f"{name}: {NUM_REQUESTS} keys over {NUM_CONNECTIONS} connections in {duration:.2f} seconds")
+
+async def main():
+    await run("Pipelined SET", pipeline_sets)
+    await run("MSET", batch_sets)
 
 if __name__ == "__main__":
     asyncio.run(main())